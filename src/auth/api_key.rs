@@ -1,20 +1,63 @@
 use std::{convert::TryFrom as _, fmt};
 
-use hyper::{http::uri::PathAndQuery, Request, Uri};
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    http::uri::PathAndQuery,
+    Request, Uri,
+};
+
+use crate::auth;
+
+/// Where API keys are put in requests.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ApiKeyPlacement {
+    /// The `x-goog-api-key` header, which keeps the key out of access logs.
+    #[default]
+    Header,
+    /// The `key` query parameter.
+    Query,
+}
+
+const X_GOOG_API_KEY: HeaderName = HeaderName::from_static("x-goog-api-key");
 
 // https://cloud.google.com/docs/authentication/api-keys
 #[derive(Clone)]
 pub(super) struct ApiKey {
     value: String,
+    header: HeaderValue,
+    placement: ApiKeyPlacement,
 }
 
 impl ApiKey {
-    pub fn new(key: impl Into<String>) -> Self {
-        Self { value: key.into() }
+    pub fn new(key: impl Into<String>, placement: ApiKeyPlacement) -> Self {
+        let value = key.into();
+        // The key is validated as a query string, which is stricter than a header value.
+        let mut header = HeaderValue::from_str(&value).unwrap();
+        header.set_sensitive(true);
+        Self {
+            value,
+            header,
+            placement,
+        }
+    }
+
+    #[inline]
+    pub fn add_key<B>(&self, req: Request<B>) -> auth::Result<Request<B>> {
+        match self.placement {
+            ApiKeyPlacement::Header => Ok(self.add_header(req)),
+            ApiKeyPlacement::Query => self.add_query(req),
+        }
+    }
+
+    #[inline]
+    fn add_header<B>(&self, mut req: Request<B>) -> Request<B> {
+        req.headers_mut()
+            .insert(X_GOOG_API_KEY, self.header.clone());
+        req
     }
 
     #[inline]
-    pub fn add_query<B>(&self, req: Request<B>) -> Request<B> {
+    fn add_query<B>(&self, req: Request<B>) -> auth::Result<Request<B>> {
         let (mut head, body) = req.into_parts();
         let s = {
             let mut s = head.uri.path().to_owned();
@@ -31,15 +74,40 @@ impl ApiKey {
         };
 
         let mut parts = head.uri.into_parts();
-        parts.path_and_query = Some(PathAndQuery::try_from(s).unwrap());
+        parts.path_and_query =
+            Some(PathAndQuery::try_from(s).map_err(|e| auth::Error::ApiKeyQuery(e.into()))?);
 
-        head.uri = Uri::from_parts(parts).unwrap();
-        Request::from_parts(head, body)
+        head.uri = Uri::from_parts(parts).map_err(|e| auth::Error::ApiKeyQuery(e.into()))?;
+        Ok(Request::from_parts(head, body))
     }
 }
 
 impl fmt::Debug for ApiKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("ApiKey").finish()
+        f.debug_struct("ApiKey")
+            .field("placement", &self.placement)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_add_key() {
+        let request = |uri: &str| Request::get(uri).body(()).unwrap();
+
+        let key = ApiKey::new("secret", ApiKeyPlacement::Header);
+        let req = key.add_key(request("https://example.com/v1?a=b")).unwrap();
+        assert_eq!(req.uri(), "https://example.com/v1?a=b");
+        assert_eq!(req.headers()[X_GOOG_API_KEY], "secret");
+
+        let key = ApiKey::new("secret", ApiKeyPlacement::Query);
+        let req = key.add_key(request("https://example.com/v1?a=b")).unwrap();
+        assert_eq!(req.uri(), "https://example.com/v1?a=b&key=secret");
+        assert!(req.headers().get(X_GOOG_API_KEY).is_none());
+        let req = key.add_key(request("https://example.com")).unwrap();
+        assert_eq!(req.uri(), "https://example.com/?key=secret");
     }
 }
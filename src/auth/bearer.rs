@@ -0,0 +1,98 @@
+use std::{
+    fmt,
+    time::{Instant, SystemTime},
+};
+
+use hyper::{
+    header::{HeaderValue, AUTHORIZATION},
+    Request,
+};
+
+use crate::{
+    auth::{self, oauth2::token::EXPIRY_DELTA},
+    credentials,
+};
+
+// A token given as is, without a way to refresh it.
+#[derive(Clone)]
+pub(super) struct Bearer {
+    value: HeaderValue,
+    expiry: Option<Instant>,
+}
+
+impl Bearer {
+    pub fn new(at: credentials::AccessToken) -> Self {
+        Self {
+            value: HeaderValue::from_str(&format!("Bearer {}", at.token)).unwrap(),
+            expiry: at.expiry.map(instant),
+        }
+    }
+
+    #[inline]
+    pub fn add_header<B>(&self, mut req: Request<B>) -> auth::Result<Request<B>> {
+        // Like refreshed tokens, it is no longer used shortly before it expires.
+        let expired = |expiry: Instant| {
+            expiry
+                .checked_duration_since(Instant::now())
+                .map(|dur| dur < EXPIRY_DELTA)
+                .unwrap_or(true)
+        };
+        if matches!(self.expiry, Some(expiry) if expired(expiry)) {
+            return Err(auth::Error::TokenExpired);
+        }
+        req.headers_mut().insert(AUTHORIZATION, self.value.clone());
+        Ok(req)
+    }
+}
+
+impl fmt::Debug for Bearer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bearer")
+            .field("expiry", &self.expiry)
+            .finish()
+    }
+}
+
+/// Converts a wall clock time to an `Instant`, saturating at now for past times.
+pub(super) fn instant(at: SystemTime) -> Instant {
+    let now = Instant::now();
+    match at.duration_since(SystemTime::now()) {
+        Ok(dur) => now + dur,
+        Err(_) => now,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_add_header() {
+        let bearer = |expiry| {
+            Bearer::new(credentials::AccessToken {
+                token: "token".into(),
+                expiry,
+                refresh: None,
+                quota_project_id: None,
+            })
+        };
+
+        let req = bearer(None).add_header(Request::new(())).unwrap();
+        assert_eq!(req.headers()[AUTHORIZATION], "Bearer token");
+        let expiry = SystemTime::now() + Duration::from_secs(60);
+        assert!(bearer(Some(expiry)).add_header(Request::new(())).is_ok());
+        // It expires within `EXPIRY_DELTA`.
+        let expiry = SystemTime::now() + Duration::from_secs(5);
+        assert!(matches!(
+            bearer(Some(expiry)).add_header(Request::new(())),
+            Err(auth::Error::TokenExpired)
+        ));
+        let expiry = SystemTime::now() - Duration::from_secs(60);
+        assert!(matches!(
+            bearer(Some(expiry)).add_header(Request::new(())),
+            Err(auth::Error::TokenExpired)
+        ));
+    }
+}
@@ -1,14 +1,68 @@
+use std::time::Duration;
+
+use hyper::{header::RETRY_AFTER, StatusCode};
+
 /// Represents errors that can occur during fetching token.
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("gcemeta client error: {0}")]
-    Gcemeta(#[from] gcemeta::Error),
+    Gcemeta(Box<gcemeta::Error>),
     #[error("http client error: {0}")]
     Http(#[from] hyper::Error),
     #[error("response status code error: {0:?}")]
-    StatusCode((hyper::http::response::Parts, hyper::Body)),
+    StatusCode(Box<(hyper::http::response::Parts, hyper::Body)>),
     #[error("response body deserialize error: {0}")]
     JsonDeserialize(serde_json::Error),
+    #[error("read subject token file error: {0}")]
+    SubjectTokenFile(std::io::Error),
+    #[error("subject token format error: {0}")]
+    SubjectTokenFormat(String),
+    #[error("credential source error: {0}")]
+    CredentialSource(String),
+    #[error("executables are not allowed, set `GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES=1` to allow them")]
+    ExecutablesNotAllowed,
+    #[error("executable error: {0}")]
+    Executable(std::io::Error),
+    #[error("executable timed out after {0:?}")]
+    ExecutableTimeout(std::time::Duration),
+    #[error("executable exited with {0}")]
+    ExecutableStatus(std::process::ExitStatus),
+    #[error("executable response error: code={code}, message={message}")]
+    ExecutableResponse { code: String, message: String },
+    #[error("executable response format error: {0}")]
+    ExecutableResponseFormat(String),
+    #[error("jwt error: {0}")]
+    Jwt(#[from] jsonwebtoken::errors::Error),
+    #[error("token request timed out after {0:?}")]
+    Timeout(std::time::Duration),
+    #[error("token fetching is paused for {retry_in:?} after it failed: {last_error}")]
+    RefreshCooldown {
+        retry_in: std::time::Duration,
+        last_error: String,
+    },
+    #[error("access token is expired and cannot be refreshed")]
+    TokenExpired,
+    #[error("id token is expired or about to expire on arrival, the local clock may be ahead of the issuer")]
+    IdTokenExpired,
+    #[error("token of the subject is still being fetched")]
+    TokenFetching,
+    #[error("access token refresh error: {0}")]
+    TokenRefresh(Box<dyn std::error::Error + Send + Sync>),
+    #[error("subject is not registered or not supported by the credentials: {0}")]
+    UnsupportedSubject(String),
+    #[error(
+        "request host {host} is not in the universe domain of the credentials: {universe_domain}"
+    )]
+    UniverseDomain {
+        host: String,
+        universe_domain: String,
+    },
+    #[error("api key query error: {0}")]
+    ApiKeyQuery(hyper::http::Error),
+    #[error("quota project id format error: {0}")]
+    QuotaProjectFormat(String),
+    #[error("token response does not contain an id token, the refresh token must be granted the `openid` scope")]
+    MissingIdToken,
     #[error("token format error: {0:?}")]
     TokenFormat(crate::auth::oauth2::token::Response),
     #[cfg(not(feature = "tonic"))]
@@ -16,5 +70,121 @@ pub enum Error {
     EnforceHttps(Option<String>),
 }
 
+/// The longest delay taken from a `Retry-After` header, so that a bogus value does not stop
+/// fetching for good.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
 /// Wrapper for the `Result` type with an [`Error`](Error).
 pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+impl From<gcemeta::Error> for Error {
+    fn from(err: gcemeta::Error) -> Self {
+        Self::Gcemeta(Box::new(err))
+    }
+}
+
+impl Error {
+    /// Reports whether fetching the token again may succeed, such as after network errors, 5xx
+    /// and 429 responses, as opposed to errors in the credentials or the response that will not
+    /// go away, such as a 400 `invalid_grant` response or a malformed private key.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Http(_)
+            | Self::SubjectTokenFile(_)
+            | Self::ExecutableTimeout(_)
+            | Self::Timeout(_)
+            | Self::RefreshCooldown { .. }
+            | Self::TokenFetching
+            | Self::TokenRefresh(_) => true,
+            Self::Gcemeta(err) if matches!(**err, gcemeta::Error::Http(_)) => true,
+            _ => self
+                .parts()
+                .is_some_and(|parts| is_retryable_status(parts.status)),
+        }
+    }
+
+    /// Returns the delay asked for by the `Retry-After` header of a 429 or 503 response. Only the
+    /// number of seconds is supported, not a date, and the delay is capped at an hour.
+    pub(crate) fn retry_after(&self) -> Option<Duration> {
+        let parts = self.parts()?;
+        if !matches!(
+            parts.status,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+        ) {
+            return None;
+        }
+        let secs = parts.headers.get(RETRY_AFTER)?.to_str().ok()?;
+        let secs = secs.trim().parse().ok()?;
+        Some(Duration::from_secs(secs).min(MAX_RETRY_AFTER))
+    }
+
+    fn parts(&self) -> Option<&hyper::http::response::Parts> {
+        match self {
+            Self::StatusCode(resp) => Some(&resp.0),
+            Self::Gcemeta(err) => match &**err {
+                gcemeta::Error::StatusCode((parts, _)) => Some(parts),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{Body, Response};
+
+    use super::*;
+
+    fn status_code(status: u16, retry_after: Option<&str>) -> Error {
+        let mut builder = Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            builder = builder.header(RETRY_AFTER, retry_after);
+        }
+        Error::StatusCode(Box::new(builder.body(Body::empty()).unwrap().into_parts()))
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        assert!(status_code(500, None).is_retryable());
+        assert!(status_code(503, None).is_retryable());
+        assert!(status_code(429, None).is_retryable());
+        assert!(status_code(408, None).is_retryable());
+        assert!(!status_code(400, None).is_retryable());
+        assert!(!status_code(401, None).is_retryable());
+        assert!(!status_code(404, None).is_retryable());
+        assert!(Error::ExecutableTimeout(Duration::from_secs(1)).is_retryable());
+        assert!(Error::Timeout(Duration::from_secs(1)).is_retryable());
+        assert!(!Error::CredentialSource("invalid role".into()).is_retryable());
+        assert!(!Error::MissingIdToken.is_retryable());
+        assert!(!Error::TokenExpired.is_retryable());
+    }
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!(
+            status_code(429, Some("7")).retry_after(),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(
+            status_code(503, Some("120")).retry_after(),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(status_code(503, None).retry_after(), None);
+        assert_eq!(
+            status_code(503, Some("Wed, 21 Oct 2015 07:28:00 GMT")).retry_after(),
+            None
+        );
+        assert_eq!(status_code(500, Some("7")).retry_after(), None);
+        assert_eq!(
+            status_code(429, Some("18446744073709551615")).retry_after(),
+            Some(MAX_RETRY_AFTER)
+        );
+    }
+}
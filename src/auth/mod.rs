@@ -1,21 +1,67 @@
-use std::task::{self, Poll};
+use std::{
+    fmt,
+    sync::Arc,
+    task::{self, Poll},
+    time::Duration,
+};
 
-use hyper::Request;
+use hyper::{
+    header::{HeaderName, HeaderValue},
+    Request,
+};
 
-use crate::Credentials;
+use crate::{
+    credentials::{CredentialSource, DEFAULT_UNIVERSE_DOMAIN},
+    runtime::{Executor, Timer},
+    Credentials,
+};
 
 mod api_key;
+mod bearer;
 mod error;
-mod oauth2;
+pub(crate) mod oauth2;
 
+pub use api_key::ApiKeyPlacement;
 pub use error::*;
-use oauth2::{token::Fetcher, Metadata, Oauth2, ServiceAccount, User};
+pub(crate) use oauth2::token::{RefreshPolicy, RefreshWindow, RetryPolicy};
+use oauth2::{
+    token::Fetcher, AccessToken, Aws, Executable, ExternalAccount, Impersonated, Metadata, Oauth2,
+    ServiceAccount, User,
+};
+pub use oauth2::{IdTokenClaims, InstanceIdentity, RefreshStatus};
 
-#[derive(Clone, Debug)]
+/// A request extension that selects the Google Workspace user to act as, through domain-wide
+/// delegation, for a single request. It takes precedence over the subject set on the
+/// credentials builder, is only supported by service account credentials, and must be
+/// registered with the `subjects` method of [`GoogleAuthz::builder`](crate::GoogleAuthz::builder).
+/// Until the first token of the user arrives, such requests fail with the retryable
+/// `AuthError::TokenFetching`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Subject(pub String);
+
+/// A request extension that selects the project billed for a single request. It takes
+/// precedence over the quota project of the credentials.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct QuotaProject(pub String);
+
+// https://cloud.google.com/apis/docs/system-parameters
+const X_GOOG_USER_PROJECT: HeaderName = HeaderName::from_static("x-goog-user-project");
+
+#[derive(Clone)]
 pub(crate) struct Config {
     #[cfg(not(feature = "tonic"))]
     pub enforce_https: bool,
     pub max_retry: u8,
+    pub api_key_placement: ApiKeyPlacement,
+    pub refresh: RefreshPolicy,
+    pub retry_cooldown: Duration,
+    pub keep_valid_token: bool,
+    pub background_refresh: Option<(Arc<dyn Executor>, Arc<dyn Timer>)>,
+    pub retry: RetryPolicy,
+    pub subjects: Vec<String>,
+    pub connect_timeout: Duration,
+    pub fetch_timeout: Duration,
+    pub timer: Option<Arc<dyn Timer>>,
 }
 
 impl Default for Config {
@@ -24,27 +70,91 @@ impl Default for Config {
             #[cfg(not(feature = "tonic"))]
             enforce_https: true,
             max_retry: 3,
+            api_key_placement: ApiKeyPlacement::default(),
+            refresh: RefreshPolicy::default(),
+            retry_cooldown: Duration::from_secs(5),
+            keep_valid_token: true,
+            background_refresh: None,
+            retry: RetryPolicy::default(),
+            subjects: Vec::new(),
+            connect_timeout: oauth2::http::CONNECT_TIMEOUT,
+            fetch_timeout: Duration::from_secs(20),
+            timer: crate::runtime::default_timer(),
         }
     }
 }
 
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("Config");
+        #[cfg(not(feature = "tonic"))]
+        f.field("enforce_https", &self.enforce_https);
+        f.field("max_retry", &self.max_retry)
+            .field("api_key_placement", &self.api_key_placement)
+            .field("refresh", &self.refresh)
+            .field("retry", &self.retry)
+            .field("subjects", &self.subjects)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("fetch_timeout", &self.fetch_timeout)
+            .field("retry_cooldown", &self.retry_cooldown)
+            .field("keep_valid_token", &self.keep_valid_token)
+            .field("background_refresh", &self.background_refresh.is_some())
+            .finish()
+    }
+}
+
 #[derive(Clone, Debug)]
 enum Inner {
     None,
     ApiKey(api_key::ApiKey),
+    Bearer(bearer::Bearer),
+    JwtAccess(oauth2::JwtAccess),
     Oauth2(oauth2::Oauth2),
 }
 
 impl From<(Credentials, &Config)> for Inner {
     fn from((credentials, config): (Credentials, &Config)) -> Self {
-        let fetcher: Box<dyn Fetcher> = match credentials {
-            Credentials::None => return Self::None,
-            Credentials::ApiKey(key) => return Self::ApiKey(api_key::ApiKey::new(key)),
-            Credentials::User(user) => Box::new(User::new(user)),
-            Credentials::ServiceAccount(sa) => Box::new(ServiceAccount::new(sa)),
-            Credentials::Metadata(meta) => Box::new(Metadata::new(meta)),
-        };
-        Self::Oauth2(Oauth2::new(fetcher, config.max_retry))
+        match credentials {
+            Credentials::None => Self::None,
+            Credentials::ApiKey(key) => {
+                Self::ApiKey(api_key::ApiKey::new(key, config.api_key_placement))
+            }
+            Credentials::AccessToken(at) if at.refresh.is_none() => {
+                Self::Bearer(bearer::Bearer::new(at))
+            }
+            Credentials::ServiceAccount(sa) if sa.self_signed_jwt => {
+                Self::JwtAccess(oauth2::JwtAccess::new(sa))
+            }
+            credentials => Self::Oauth2(Oauth2::new(
+                fetcher(credentials, config.connect_timeout),
+                config,
+            )),
+        }
+    }
+}
+
+fn fetcher(credentials: Credentials, connect_timeout: Duration) -> Box<dyn Fetcher> {
+    match credentials {
+        Credentials::None | Credentials::ApiKey(_) => {
+            unreachable!("credentials without oauth2 tokens: {:?}", credentials)
+        }
+        Credentials::AccessToken(at) => Box::new(AccessToken::new(at)),
+        Credentials::User(user) => Box::new(User::new(user, connect_timeout)),
+        Credentials::ServiceAccount(sa) => Box::new(ServiceAccount::new(sa, connect_timeout)),
+        Credentials::Metadata(meta) => Box::new(Metadata::new(meta)),
+        Credentials::ExternalAccount(ea) => match ea.credential_source {
+            CredentialSource::Aws { .. } => Box::new(Aws::new(ea, connect_timeout)),
+            CredentialSource::Executable { .. } => Box::new(Executable::new(ea, connect_timeout)),
+            _ => Box::new(ExternalAccount::new(ea, connect_timeout)),
+        },
+        Credentials::Impersonated(mut imp) => {
+            let source = std::mem::replace(&mut imp.source, Credentials::None);
+            Box::new(Impersonated::new(
+                *imp,
+                fetcher(source, connect_timeout),
+                connect_timeout,
+            ))
+        }
     }
 }
 
@@ -52,14 +162,27 @@ impl From<(Credentials, &Config)> for Inner {
 #[derive(Clone, Debug)]
 pub(crate) struct Auth {
     inner: Inner,
+    quota_project: Option<HeaderValue>,
+    // Only set outside of the default universe, where requests go to custom endpoints as well.
+    universe_domain: Option<String>,
     #[cfg(not(feature = "tonic"))]
     enforce_https: bool,
 }
 
 impl Auth {
     pub fn new(credentials: Credentials, config: Config) -> Self {
+        // The quota project is validated when the credentials are built.
+        let quota_project = credentials
+            .quota_project_id()
+            .and_then(|project| HeaderValue::from_str(project).ok());
+        let universe_domain = credentials
+            .universe_domain()
+            .filter(|&domain| domain != DEFAULT_UNIVERSE_DOMAIN)
+            .map(ToOwned::to_owned);
         Self {
             inner: (credentials, &config).into(),
+            quota_project,
+            universe_domain,
             #[cfg(not(feature = "tonic"))]
             enforce_https: config.enforce_https,
         }
@@ -73,21 +196,62 @@ impl Auth {
         }
     }
 
+    pub fn refresh_status(&self) -> Option<RefreshStatus> {
+        match self.inner {
+            Inner::Oauth2(ref oauth2) => Some(oauth2.status()),
+            _ => None,
+        }
+    }
+
     #[inline]
-    pub fn call<B>(&self, req: Request<B>) -> Result<Request<B>> {
+    pub fn call<B>(&self, mut req: Request<B>) -> Result<Request<B>> {
         #[cfg(not(feature = "tonic"))]
         if self.enforce_https {
             check_https(req.uri().scheme_str())?;
         }
 
+        if let (Some(domain), Some(host)) = (&self.universe_domain, req.uri().host()) {
+            check_universe_domain(domain, host)?;
+        }
+
+        let quota_project = match req.extensions().get() {
+            Some(QuotaProject(project)) => Some(
+                HeaderValue::from_str(project)
+                    .map_err(|_| Error::QuotaProjectFormat(project.clone()))?,
+            ),
+            None => self.quota_project.clone(),
+        };
+        if let Some(value) = quota_project {
+            req.headers_mut().insert(X_GOOG_USER_PROJECT, value);
+        }
+
+        if let Some(Subject(subject)) = req.extensions().get() {
+            if !matches!(self.inner, Inner::Oauth2(_)) {
+                return Err(Error::UnsupportedSubject(subject.clone()));
+            }
+        }
+
         match self.inner {
             Inner::None => Ok(req),
-            Inner::ApiKey(ref key) => Ok(key.add_query(req)),
-            Inner::Oauth2(ref oauth2) => Ok(oauth2.add_header(req)),
+            Inner::ApiKey(ref key) => key.add_key(req),
+            Inner::Bearer(ref bearer) => bearer.add_header(req),
+            Inner::JwtAccess(ref jwt) => jwt.add_header(req),
+            Inner::Oauth2(ref oauth2) => oauth2.call(req),
         }
     }
 }
 
+#[inline]
+fn check_universe_domain(domain: &str, host: &str) -> Result<()> {
+    match host.strip_suffix(domain) {
+        Some(prefix) if prefix.is_empty() || prefix.ends_with('.') => Ok(()),
+        _ => Err(Error::UniverseDomain {
+            host: host.to_owned(),
+            universe_domain: domain.to_owned(),
+        }),
+    }
+}
+
 #[inline]
 #[cfg(not(feature = "tonic"))]
 fn check_https(scheme: Option<&'_ str>) -> Result<()> {
@@ -96,3 +260,61 @@ fn check_https(scheme: Option<&'_ str>) -> Result<()> {
         _ => Err(Error::EnforceHttps(scheme.map(ToOwned::to_owned))),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check_universe_domain() {
+        assert!(check_universe_domain("example.goog", "example.goog").is_ok());
+        assert!(check_universe_domain("example.goog", "storage.example.goog").is_ok());
+        assert!(matches!(
+            check_universe_domain("example.goog", "storage.googleapis.com"),
+            Err(Error::UniverseDomain { host, .. }) if host == "storage.googleapis.com"
+        ));
+        assert!(check_universe_domain("example.goog", "badexample.goog").is_err());
+    }
+
+    #[test]
+    fn test_quota_project() {
+        let mut auth = Auth::new(Credentials::None, Config::default());
+        let request = |project: Option<&str>| {
+            let mut req = Request::get("https://example.com").body(()).unwrap();
+            if let Some(project) = project {
+                req.extensions_mut().insert(QuotaProject(project.into()));
+            }
+            req
+        };
+        let header =
+            |req: Result<Request<()>>| req.unwrap().headers().get(X_GOOG_USER_PROJECT).cloned();
+
+        assert_eq!(header(auth.call(request(None))), None);
+        auth.quota_project = Some(HeaderValue::from_static("default"));
+        assert_eq!(header(auth.call(request(None))).unwrap(), "default");
+        assert_eq!(
+            header(auth.call(request(Some("override")))).unwrap(),
+            "override"
+        );
+        assert!(matches!(
+            auth.call(request(Some("invalid\n"))),
+            Err(Error::QuotaProjectFormat(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_access_token_quota_project() {
+        let credentials = Credentials::builder()
+            .access_token("token", None)
+            .quota_project_id("project")
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(credentials.quota_project_id(), Some("project"));
+        let auth = Auth::new(credentials, Config::default());
+        let req = auth
+            .call(Request::get("https://example.com").body(()).unwrap())
+            .unwrap();
+        assert_eq!(req.headers()[X_GOOG_USER_PROJECT], "project");
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    auth::{self, oauth2::token},
+    credentials,
+};
+
+// Tokens without expiry are cached for a long time instead.
+const NEVER: u64 = 100 * 365 * 24 * 60 * 60;
+
+// An access token given as is, refreshed through the user's callback if any.
+pub struct AccessToken {
+    current: Arc<Mutex<(String, Option<SystemTime>)>>,
+    refresh: Option<credentials::Refresh>,
+    // Whether the given token has been fetched, so that later fetches refresh it.
+    fetched: Arc<AtomicBool>,
+}
+
+impl AccessToken {
+    pub(crate) fn new(at: credentials::AccessToken) -> Self {
+        Self {
+            current: Arc::new(Mutex::new((at.token, at.expiry))),
+            refresh: at.refresh,
+            fetched: Default::default(),
+        }
+    }
+}
+
+impl fmt::Debug for AccessToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessToken")
+            .field("expiry", &self.current.lock().1)
+            .finish()
+    }
+}
+
+impl token::Fetcher for AccessToken {
+    fn fetch(&self) -> token::ResponseFuture {
+        let (current, refresh) = (self.current.clone(), self.refresh.clone());
+        let fetched = self.fetched.swap(true, Ordering::SeqCst);
+        Box::pin(async move {
+            let (token, expiry) = current.lock().clone();
+            let (token, expiry) = match refresh {
+                // Fetches are only made when the token needs a refresh, which the callback does.
+                Some(refresh) if fetched || expires_in(expiry).is_none() => {
+                    let (token, expiry) =
+                        refresh.call().await.map_err(auth::Error::TokenRefresh)?;
+                    *current.lock() = (token.clone(), expiry);
+                    (token, expiry)
+                }
+                _ => (token, expiry),
+            };
+            let expires_in = expires_in(expiry).ok_or(auth::Error::TokenExpired)?;
+            Ok(token::Response::AccessToken {
+                token_type: "Bearer".into(),
+                access_token: token,
+                expires_in,
+            })
+        })
+    }
+}
+
+/// Returns the seconds until `expiry`, or `None` if the token is about to expire.
+fn expires_in(expiry: Option<SystemTime>) -> Option<u64> {
+    let expiry = match expiry {
+        Some(expiry) => expiry,
+        None => return Some(NEVER),
+    };
+    expiry
+        .duration_since(SystemTime::now())
+        .ok()
+        .filter(|dur| *dur >= token::EXPIRY_DELTA)
+        .map(|dur| dur.as_secs())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::atomic::AtomicUsize, time::Duration};
+
+    use futures_util::FutureExt as _;
+
+    use super::*;
+    use crate::auth::oauth2::token::Fetcher as _;
+
+    fn access_token(expiry: SystemTime, refresh: Option<credentials::Refresh>) -> AccessToken {
+        AccessToken::new(credentials::AccessToken {
+            token: "initial".into(),
+            expiry: Some(expiry),
+            refresh,
+            quota_project_id: None,
+        })
+    }
+
+    fn access_token_of(resp: token::Response) -> String {
+        match resp {
+            token::Response::AccessToken { access_token, .. } => access_token,
+            resp => panic!("unexpected response: {:?}", resp),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let valid = SystemTime::now() + Duration::from_secs(60 * 60);
+        let expired = SystemTime::now() - Duration::from_secs(60);
+
+        let fetcher = access_token(valid, None);
+        assert_eq!(access_token_of(fetcher.fetch().await.unwrap()), "initial");
+        let fetcher = access_token(expired, None);
+        assert!(matches!(
+            fetcher.fetch().await,
+            Err(auth::Error::TokenExpired)
+        ));
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let refresh = {
+            let calls = calls.clone();
+            credentials::Refresh::new(move || {
+                let n = calls.fetch_add(1, Ordering::SeqCst);
+                async move { Ok((format!("refreshed-{}", n), Some(valid))) }.boxed()
+            })
+        };
+        let fetcher = access_token(valid, Some(refresh.clone()));
+        assert_eq!(access_token_of(fetcher.fetch().await.unwrap()), "initial");
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        let fetcher = access_token(expired, Some(refresh));
+        assert_eq!(
+            access_token_of(fetcher.fetch().await.unwrap()),
+            "refreshed-0"
+        );
+        // Every later fetch is a refresh, even ahead of the expiry of the current token.
+        assert_eq!(
+            access_token_of(fetcher.fetch().await.unwrap()),
+            "refreshed-1"
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
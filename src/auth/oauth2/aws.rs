@@ -0,0 +1,452 @@
+use std::{
+    env, fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use hyper::{
+    header::{HeaderMap, HeaderValue},
+    Uri,
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use ring::{digest, hmac};
+
+use crate::{
+    auth::{
+        self,
+        oauth2::{datetime::civil_from_days, external_account::Sts, http::Client, token},
+    },
+    credentials::{self, CredentialSource},
+};
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    token: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct SubjectToken<'a> {
+    url: &'a str,
+    method: &'a str,
+    headers: Vec<SubjectTokenHeader<'a>>,
+}
+
+#[derive(serde::Serialize)]
+struct SubjectTokenHeader<'a> {
+    key: &'a str,
+    value: &'a str,
+}
+
+struct Source {
+    inner: Client,
+    region_url: Option<Uri>,
+    url: Option<Uri>,
+    regional_cred_verification_url: String,
+    imdsv2_session_token_url: Option<Uri>,
+    audience: String,
+    getenv: fn(&str) -> Option<String>,
+}
+
+// https://cloud.google.com/iam/docs/workload-identity-federation-with-other-clouds
+pub struct Aws {
+    sts: Sts,
+    source: Arc<Source>,
+}
+
+impl Aws {
+    pub(crate) fn new(ea: credentials::ExternalAccount, connect_timeout: Duration) -> Self {
+        let sts = Sts::new(&ea, connect_timeout);
+        let source = match ea.credential_source {
+            CredentialSource::Aws {
+                region_url,
+                url,
+                regional_cred_verification_url,
+                imdsv2_session_token_url,
+                ..
+            } => Source {
+                // The metadata endpoints of EC2 are only served over plain http.
+                inner: Client::https_or_http(connect_timeout),
+                region_url: region_url.map(parse_url),
+                url: url.map(parse_url),
+                regional_cred_verification_url,
+                imdsv2_session_token_url: imdsv2_session_token_url.map(parse_url),
+                audience: sts.audience().to_owned(),
+                getenv: |name| env::var(name).ok().filter(|v| !v.is_empty()),
+            },
+            _ => unreachable!("credential source must be aws"),
+        };
+        Self {
+            sts,
+            source: Arc::new(source),
+        }
+    }
+}
+
+impl fmt::Debug for Aws {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Aws").finish()
+    }
+}
+
+impl token::Fetcher for Aws {
+    fn fetch(&self) -> token::ResponseFuture {
+        let source = self.source.clone();
+        self.sts
+            .exchange(Box::pin(async move { source.subject_token().await }))
+    }
+}
+
+impl Source {
+    async fn subject_token(&self) -> auth::Result<String> {
+        let region = (self.getenv)("AWS_REGION").or_else(|| (self.getenv)("AWS_DEFAULT_REGION"));
+        let credentials = match (
+            (self.getenv)("AWS_ACCESS_KEY_ID"),
+            (self.getenv)("AWS_SECRET_ACCESS_KEY"),
+        ) {
+            (Some(access_key_id), Some(secret_access_key)) => Some(AwsCredentials {
+                access_key_id,
+                secret_access_key,
+                token: (self.getenv)("AWS_SESSION_TOKEN"),
+            }),
+            _ => None,
+        };
+
+        // IMDSv2 requires a session token for every metadata request.
+        let mut headers = HeaderMap::new();
+        if region.is_none() || credentials.is_none() {
+            if let Some(ref uri) = self.imdsv2_session_token_url {
+                let mut h = HeaderMap::new();
+                h.insert(
+                    "x-aws-ec2-metadata-token-ttl-seconds",
+                    HeaderValue::from_static("300"),
+                );
+                let token = self.get_text(self.inner.put(uri, &h)).await?;
+                headers.insert(
+                    "x-aws-ec2-metadata-token",
+                    HeaderValue::from_str(&token).map_err(|_| {
+                        auth::Error::CredentialSource("invalid imdsv2 session token".into())
+                    })?,
+                );
+            }
+        }
+
+        let region = match region {
+            Some(region) => region,
+            None => {
+                let uri = self.region_url.as_ref().ok_or_else(|| {
+                    auth::Error::CredentialSource("unable to determine aws region".into())
+                })?;
+                // The metadata server returns the availability zone, e.g. `us-east-2b`.
+                let mut zone = self.get_text(self.inner.get(uri, &headers)).await?;
+                zone.pop();
+                zone
+            }
+        };
+
+        let credentials = match credentials {
+            Some(credentials) => credentials,
+            None => {
+                let uri = self.url.as_ref().ok_or_else(|| {
+                    auth::Error::CredentialSource("unable to determine aws credentials".into())
+                })?;
+                let role = self.get_text(self.inner.get(uri, &headers)).await?;
+                let uri = format!("{}/{}", uri.to_string().trim_end_matches('/'), role)
+                    .parse::<Uri>()
+                    .map_err(|_| {
+                        auth::Error::CredentialSource(format!("invalid role: {}", role))
+                    })?;
+                self.inner.send(self.inner.get(&uri, &headers)).await?
+            }
+        };
+
+        let url = self
+            .regional_cred_verification_url
+            .replace("{region}", &region);
+        let uri = url.parse::<Uri>().map_err(|_| {
+            auth::Error::CredentialSource(format!("invalid verification url: {}", url))
+        })?;
+        let mut headers = vec![
+            (
+                "host".to_owned(),
+                uri.authority().map_or("", |a| a.as_str()).to_owned(),
+            ),
+            ("x-amz-date".to_owned(), amz_date(SystemTime::now())),
+            (
+                "x-goog-cloud-target-resource".to_owned(),
+                self.audience.clone(),
+            ),
+        ];
+        if let Some(ref token) = credentials.token {
+            headers.push(("x-amz-security-token".to_owned(), token.clone()));
+        }
+        let authorization = sign("POST", &uri, &headers, &credentials, &region, "sts");
+        headers.push(("Authorization".to_owned(), authorization));
+
+        let token = SubjectToken {
+            url: &url,
+            method: "POST",
+            headers: headers
+                .iter()
+                .map(|(key, value)| SubjectTokenHeader { key, value })
+                .collect(),
+        };
+        let token = serde_json::to_string(&token).unwrap();
+        Ok(utf8_percent_encode(&token, NON_ALPHANUMERIC).to_string())
+    }
+
+    async fn get_text(&self, req: hyper::Request<hyper::Body>) -> auth::Result<String> {
+        let buf: Bytes = self.inner.send_bytes(req).await?;
+        String::from_utf8(buf.to_vec())
+            .map(|s| s.trim().to_owned())
+            .map_err(|err| auth::Error::CredentialSource(err.to_string()))
+    }
+}
+
+// https://docs.aws.amazon.com/general/latest/gr/sigv4_signing.html
+fn sign(
+    method: &str,
+    uri: &Uri,
+    headers: &[(String, String)],
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+) -> String {
+    let mut headers = headers
+        .iter()
+        .map(|(k, v)| (k.to_lowercase(), v.trim()))
+        .collect::<Vec<_>>();
+    headers.sort();
+    let amz_date = headers
+        .iter()
+        .find(|(k, _)| k == "x-amz-date")
+        .map(|(_, v)| *v)
+        .expect("x-amz-date header");
+    let date = &amz_date[..8];
+
+    let mut query = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    query.sort_unstable();
+
+    let canonical_headers = headers
+        .iter()
+        .map(|(k, v)| format!("{}:{}\n", k, v))
+        .collect::<String>();
+    let signed_headers = headers
+        .iter()
+        .map(|(k, _)| k.as_str())
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        method,
+        uri.path(),
+        query.join("&"),
+        canonical_headers,
+        signed_headers,
+        hex(digest::digest(&digest::SHA256, b"").as_ref()),
+    );
+
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref()),
+    );
+
+    let key = [date, region, service, "aws4_request"].iter().fold(
+        format!("AWS4{}", credentials.secret_access_key).into_bytes(),
+        |key, data| {
+            hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key), data.as_bytes())
+                .as_ref()
+                .to_vec()
+        },
+    );
+    let signature = hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA256, &key),
+        string_to_sign.as_bytes(),
+    );
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id,
+        scope,
+        signed_headers,
+        hex(signature.as_ref()),
+    )
+}
+
+fn parse_url(url: String) -> Uri {
+    Uri::from_maybe_shared(url).expect("aws credential source urls are validated")
+}
+
+fn hex(buf: &[u8]) -> String {
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Formats `t` as `YYYYMMDD'T'HHMMSS'Z'`.
+fn amz_date(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let (y, m, d) = civil_from_days(days);
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        y,
+        m,
+        d,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        convert::TryFrom as _,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use hyper::{Method, Response};
+
+    use super::*;
+    use crate::{
+        auth::oauth2::{http::CONNECT_TIMEOUT, token::Fetcher as _},
+        credentials::ExternalAccount,
+        testing,
+    };
+
+    #[test]
+    fn test_amz_date() {
+        let t = UNIX_EPOCH + Duration::from_secs(1_440_938_160);
+        assert_eq!(amz_date(t), "20150830T123600Z");
+        let t = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(amz_date(t), "20000229T000000Z");
+    }
+
+    #[test]
+    fn test_sign() {
+        // https://github.com/awslabs/aws-c-auth/tree/main/tests/aws-signing-test-suite/v4/get-vanilla
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".into(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".into(),
+            token: None,
+        };
+        let headers = [
+            ("Host".to_owned(), "example.amazonaws.com".to_owned()),
+            ("X-Amz-Date".to_owned(), "20150830T123600Z".to_owned()),
+        ];
+        assert_eq!(
+            sign(
+                "GET",
+                &Uri::from_static("https://example.amazonaws.com/"),
+                &headers,
+                &credentials,
+                "us-east-1",
+                "service"
+            ),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let subject_token = Arc::new(Mutex::new(None));
+        let captured = subject_token.clone();
+        let addr = testing::serve(move |parts, _| {
+            let session = parts.headers.get("x-aws-ec2-metadata-token");
+            let body = match (&parts.method, parts.uri.path()) {
+                (&Method::PUT, "/latest/api/token") => "session-token".to_owned(),
+                (_, "/latest/api/token") => unreachable!(),
+                _ if !matches!(session, Some(v) if v == "session-token") => {
+                    return Response::builder().status(401).body("".into()).unwrap()
+                }
+                (_, "/latest/meta-data/placement/availability-zone") => "us-east-2b".into(),
+                (_, "/latest/meta-data/iam/security-credentials") => "role".into(),
+                (_, "/latest/meta-data/iam/security-credentials/role") => {
+                    r#"{"AccessKeyId":"key-id","SecretAccessKey":"secret","Token":"token"}"#.into()
+                }
+                _ => unreachable!(),
+            };
+            Response::new(body.into())
+        });
+        let sts = testing::serve(move |parts, body| {
+            assert_eq!(parts.uri.path(), "/v1/token");
+            let form = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body).unwrap();
+            let token = form
+                .into_iter()
+                .find(|(k, _)| k == "subject_token")
+                .unwrap()
+                .1;
+            *captured.lock().unwrap() = Some(token);
+            Response::new(
+                r#"{"access_token":"access-token","issued_token_type":"urn:ietf:params:oauth:token-type:access_token","token_type":"Bearer","expires_in":3600}"#.into(),
+            )
+        });
+
+        let mut aws = Aws::new(ExternalAccount {
+            scopes: vec!["https://www.googleapis.com/auth/cloud-platform".into()],
+            audience: "audience".into(),
+            subject_token_type: "urn:ietf:params:aws:token-type:aws4_request".into(),
+            token_url: format!("http://{}/v1/token", sts),
+            credential_source: CredentialSource::Aws {
+                environment_id: "aws1".into(),
+                region_url: Some(format!(
+                    "http://{}/latest/meta-data/placement/availability-zone",
+                    addr
+                )),
+                url: Some(format!(
+                    "http://{}/latest/meta-data/iam/security-credentials",
+                    addr
+                )),
+                regional_cred_verification_url:
+                    "https://sts.{region}.amazonaws.com?Action=GetCallerIdentity&Version=2011-06-15"
+                        .into(),
+                imdsv2_session_token_url: Some(format!("http://{}/latest/api/token", addr)),
+            },
+            service_account_impersonation_url: None,
+            quota_project_id: None,
+            universe_domain: None,
+        }, CONNECT_TIMEOUT);
+        Arc::get_mut(&mut aws.source).unwrap().getenv = |_| None;
+        aws.sts.inner = Client::https_or_http(CONNECT_TIMEOUT);
+
+        let token = token::Token::try_from(aws.fetch().await.unwrap()).unwrap();
+        assert_eq!(token.value, "Bearer access-token");
+        assert!(token.expiry > Instant::now());
+
+        let subject_token = subject_token.lock().unwrap().take().unwrap();
+        let subject_token = percent_encoding::percent_decode_str(&subject_token)
+            .decode_utf8()
+            .unwrap();
+        let subject_token = serde_json::from_str::<serde_json::Value>(&subject_token).unwrap();
+        assert_eq!(
+            subject_token["url"],
+            "https://sts.us-east-2.amazonaws.com?Action=GetCallerIdentity&Version=2011-06-15"
+        );
+        assert_eq!(subject_token["method"], "POST");
+        let headers = subject_token["headers"].as_array().unwrap();
+        let header = |key: &str| {
+            headers
+                .iter()
+                .find(|h| h["key"] == key)
+                .map(|h| h["value"].as_str().unwrap().to_owned())
+        };
+        assert_eq!(header("host").unwrap(), "sts.us-east-2.amazonaws.com");
+        assert_eq!(header("x-goog-cloud-target-resource").unwrap(), "audience");
+        assert_eq!(header("x-amz-security-token").unwrap(), "token");
+        assert!(header("Authorization")
+            .unwrap()
+            .starts_with("AWS4-HMAC-SHA256 Credential=key-id/"));
+    }
+}
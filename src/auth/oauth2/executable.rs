@@ -0,0 +1,372 @@
+use std::{
+    env, fmt, fs,
+    io::Read as _,
+    process::{Command, Stdio},
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use tracing::trace;
+
+use crate::{
+    auth::{
+        self,
+        oauth2::{external_account::Sts, token},
+    },
+    credentials::{self, CredentialSource},
+    sync::blocking,
+};
+
+// https://cloud.google.com/iam/docs/workload-identity-federation-with-other-providers#create-cred-config-executable
+#[derive(Debug, serde::Deserialize)]
+struct Response {
+    version: u32,
+    success: bool,
+    token_type: Option<String>,
+    expiration_time: Option<u64>,
+    id_token: Option<String>,
+    saml_response: Option<String>,
+    code: Option<String>,
+    message: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum SubjectToken {
+    Valid(String),
+    Expired,
+}
+
+struct Source {
+    command: String,
+    timeout: Duration,
+    output_file: Option<String>,
+    audience: String,
+    subject_token_type: String,
+    getenv: fn(&str) -> Option<String>,
+}
+
+// https://cloud.google.com/iam/docs/workload-identity-federation-with-other-providers#executable-sourced-credentials
+pub struct Executable {
+    sts: Sts,
+    source: Arc<Source>,
+}
+
+impl Executable {
+    pub(crate) fn new(ea: credentials::ExternalAccount, connect_timeout: Duration) -> Self {
+        let sts = Sts::new(&ea, connect_timeout);
+        let source = match ea.credential_source {
+            CredentialSource::Executable { executable } => Source {
+                command: executable.command,
+                timeout: Duration::from_millis(executable.timeout_millis),
+                output_file: executable.output_file,
+                audience: sts.audience().to_owned(),
+                subject_token_type: sts.subject_token_type().to_owned(),
+                getenv: |name| env::var(name).ok(),
+            },
+            _ => unreachable!("credential source must be executable"),
+        };
+        Self {
+            sts,
+            source: Arc::new(source),
+        }
+    }
+}
+
+impl fmt::Debug for Executable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Executable").finish()
+    }
+}
+
+impl token::Fetcher for Executable {
+    fn fetch(&self) -> token::ResponseFuture {
+        let source = self.source.clone();
+        self.sts.exchange(blocking(move || source.subject_token()))
+    }
+}
+
+impl Source {
+    fn subject_token(&self) -> auth::Result<String> {
+        if (self.getenv)("GOOGLE_EXTERNAL_ACCOUNT_ALLOW_EXECUTABLES").as_deref() != Some("1") {
+            return Err(auth::Error::ExecutablesNotAllowed);
+        }
+
+        if let Some(ref path) = self.output_file {
+            match fs::read(path) {
+                Ok(buf) if !buf.is_empty() => {
+                    if let SubjectToken::Valid(token) = parse_response(&buf, now(), true)? {
+                        trace!("use cached executable response from {:?}", path);
+                        return Ok(token);
+                    }
+                    trace!("cached executable response is expired");
+                }
+                _ => trace!("no cached executable response at {:?}", path),
+            }
+        }
+
+        let buf = self.run()?;
+        match parse_response(&buf, now(), self.output_file.is_some())? {
+            SubjectToken::Valid(token) => Ok(token),
+            SubjectToken::Expired => Err(auth::Error::ExecutableResponseFormat(
+                "executable response is expired".into(),
+            )),
+        }
+    }
+
+    fn run(&self) -> auth::Result<Vec<u8>> {
+        let mut args = self.command.split_whitespace();
+        let mut cmd = Command::new(args.next().unwrap_or_default());
+        cmd.args(args)
+            .env("GOOGLE_EXTERNAL_ACCOUNT_AUDIENCE", &self.audience)
+            .env(
+                "GOOGLE_EXTERNAL_ACCOUNT_TOKEN_TYPE",
+                &self.subject_token_type,
+            )
+            .env("GOOGLE_EXTERNAL_ACCOUNT_INTERACTIVE", "0")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped());
+        if let Some(ref path) = self.output_file {
+            cmd.env("GOOGLE_EXTERNAL_ACCOUNT_OUTPUT_FILE", path);
+        }
+
+        trace!("run executable: {:?}", cmd);
+        let mut child = cmd.spawn().map_err(auth::Error::Executable)?;
+        let mut stdout = child.stdout.take().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let reader = thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = tx.send(stdout.read_to_end(&mut buf).map(|_| buf));
+        });
+
+        // The output is complete once the executable closes its stdout, which it does on exit.
+        let output = match rx.recv_timeout(self.timeout) {
+            Ok(output) => output,
+            Err(_) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                // Killing the executable closes its stdout, which ends the reader.
+                let _ = reader.join();
+                return Err(auth::Error::ExecutableTimeout(self.timeout));
+            }
+        };
+        let _ = reader.join();
+        let status = child.wait().map_err(auth::Error::Executable)?;
+        if !status.success() {
+            return Err(auth::Error::ExecutableStatus(status));
+        }
+        output.map_err(auth::Error::Executable)
+    }
+}
+
+fn parse_response(buf: &[u8], now: u64, require_expiration: bool) -> auth::Result<SubjectToken> {
+    let format = |msg: &str| auth::Error::ExecutableResponseFormat(msg.to_owned());
+
+    let resp = serde_json::from_slice::<Response>(buf).map_err(auth::Error::JsonDeserialize)?;
+    if resp.version != 1 {
+        return Err(format(&format!("unsupported version: {}", resp.version)));
+    }
+    if !resp.success {
+        return match (resp.code, resp.message) {
+            (Some(code), Some(message)) => Err(auth::Error::ExecutableResponse { code, message }),
+            _ => Err(format("`code` and `message` are required on failure")),
+        };
+    }
+
+    match resp.expiration_time {
+        Some(exp) if exp <= now => return Ok(SubjectToken::Expired),
+        None if require_expiration => {
+            return Err(format("`expiration_time` is required with `output_file`"))
+        }
+        _ => {}
+    }
+
+    let token = match resp.token_type.as_deref() {
+        Some("urn:ietf:params:oauth:token-type:jwt")
+        | Some("urn:ietf:params:oauth:token-type:id_token") => resp.id_token,
+        Some("urn:ietf:params:oauth:token-type:saml2") => resp.saml_response,
+        Some(typ) => return Err(format(&format!("unsupported token type: {}", typ))),
+        None => return Err(format("`token_type` is required")),
+    };
+    token
+        .filter(|t| !t.is_empty())
+        .map(SubjectToken::Valid)
+        .ok_or_else(|| format("missing subject token"))
+}
+
+fn now() -> u64 {
+    SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(
+            parse_response(
+                br#"{"version": 1, "success": true, "token_type": "urn:ietf:params:oauth:token-type:id_token", "id_token": "token", "expiration_time": 200}"#,
+                100,
+                true
+            )
+            .unwrap(),
+            SubjectToken::Valid("token".into())
+        );
+        assert_eq!(
+            parse_response(
+                br#"{"version": 1, "success": true, "token_type": "urn:ietf:params:oauth:token-type:saml2", "saml_response": "saml"}"#,
+                100,
+                false
+            )
+            .unwrap(),
+            SubjectToken::Valid("saml".into())
+        );
+        assert_eq!(
+            parse_response(
+                br#"{"version": 1, "success": true, "token_type": "urn:ietf:params:oauth:token-type:jwt", "id_token": "token", "expiration_time": 100}"#,
+                100,
+                true
+            )
+            .unwrap(),
+            SubjectToken::Expired
+        );
+        assert!(matches!(
+            parse_response(
+                br#"{"version": 1, "success": true, "token_type": "urn:ietf:params:oauth:token-type:jwt", "id_token": "token"}"#,
+                100,
+                true
+            ),
+            Err(auth::Error::ExecutableResponseFormat(_))
+        ));
+        assert!(matches!(
+            parse_response(
+                br#"{"version": 1, "success": false, "code": "401", "message": "Caller not authorized."}"#,
+                100,
+                false
+            ),
+            Err(auth::Error::ExecutableResponse { code, .. }) if code == "401"
+        ));
+        assert!(matches!(
+            parse_response(br#"{"version": 2, "success": true}"#, 100, false),
+            Err(auth::Error::ExecutableResponseFormat(_))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_fetch() {
+        use std::{convert::TryFrom as _, os::unix::fs::PermissionsExt as _};
+
+        use hyper::Response;
+
+        use crate::{
+            auth::oauth2::{
+                http::{Client, CONNECT_TIMEOUT},
+                token::Fetcher as _,
+            },
+            credentials::{Executable as Config, ExternalAccount},
+            testing,
+        };
+
+        let sts = testing::serve(|_, body| {
+            let form = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body).unwrap();
+            let token = form
+                .into_iter()
+                .find(|(k, _)| k == "subject_token")
+                .unwrap()
+                .1;
+            Response::new(
+                format!(
+                    r#"{{"access_token":"{}","token_type":"Bearer","expires_in":3600}}"#,
+                    token
+                )
+                .into(),
+            )
+        });
+
+        let dir = env::temp_dir().join(format!("google-authz-executable-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("token.sh");
+        fs::write(
+            &script,
+            r#"#!/bin/sh
+echo '{"version": 1, "success": true, "token_type": "urn:ietf:params:oauth:token-type:jwt", "id_token": "'$GOOGLE_EXTERNAL_ACCOUNT_AUDIENCE'"}'
+"#,
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let executable = |command: &str, output_file: Option<String>| {
+            let mut executable = Executable::new(
+                ExternalAccount {
+                    scopes: vec![],
+                    audience: "audience".into(),
+                    subject_token_type: "urn:ietf:params:oauth:token-type:jwt".into(),
+                    token_url: format!("http://{}/v1/token", sts),
+                    credential_source: CredentialSource::Executable {
+                        executable: Config {
+                            command: command.into(),
+                            timeout_millis: 5000,
+                            output_file,
+                        },
+                    },
+                    service_account_impersonation_url: None,
+                    quota_project_id: None,
+                    universe_domain: None,
+                },
+                CONNECT_TIMEOUT,
+            );
+            // The local token endpoint is only served over plain http.
+            executable.sts.inner = Client::https_or_http(CONNECT_TIMEOUT);
+            executable
+        };
+
+        let fetcher = executable(script.to_str().unwrap(), None);
+        assert!(matches!(
+            fetcher.fetch().await,
+            Err(auth::Error::ExecutablesNotAllowed)
+        ));
+
+        let mut fetcher = fetcher;
+        Arc::get_mut(&mut fetcher.source).unwrap().getenv = |_| Some("1".into());
+        let token = token::Token::try_from(fetcher.fetch().await.unwrap()).unwrap();
+        assert_eq!(token.value, "Bearer audience");
+
+        // A still valid cached response is used without running the command.
+        let output_file = dir.join("output.json");
+        fs::write(
+            &output_file,
+            format!(
+                r#"{{"version": 1, "success": true, "token_type": "urn:ietf:params:oauth:token-type:jwt", "id_token": "cached", "expiration_time": {}}}"#,
+                now() + 3600
+            ),
+        )
+        .unwrap();
+        let mut fetcher = executable(
+            "/does/not/exist",
+            Some(output_file.to_str().unwrap().into()),
+        );
+        Arc::get_mut(&mut fetcher.source).unwrap().getenv = |_| Some("1".into());
+        let token = token::Token::try_from(fetcher.fetch().await.unwrap()).unwrap();
+        assert_eq!(token.value, "Bearer cached");
+
+        // An executable that does not finish in time is killed.
+        let source = Source {
+            command: "sleep 10".into(),
+            timeout: Duration::from_millis(100),
+            output_file: None,
+            audience: "audience".into(),
+            subject_token_type: "urn:ietf:params:oauth:token-type:jwt".into(),
+            getenv: |_| Some("1".into()),
+        };
+        let started = std::time::Instant::now();
+        assert!(matches!(
+            source.subject_token(),
+            Err(auth::Error::ExecutableTimeout(_))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+use std::{convert::TryFrom as _, fmt, fs, time::Duration};
+
+use bytes::Bytes;
+use futures_util::{future::BoxFuture, TryFutureExt as _};
+use hyper::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    Uri,
+};
+
+use crate::{
+    auth::{
+        self,
+        oauth2::{http::Client, token},
+    },
+    credentials::{self, CredentialSource, Format},
+};
+
+#[derive(serde::Serialize)]
+struct Payload<'a> {
+    grant_type: &'a str,
+    audience: &'a str,
+    scope: &'a str,
+    requested_token_type: &'a str,
+    subject_token: &'a str,
+    subject_token_type: &'a str,
+}
+
+enum Source {
+    File {
+        path: String,
+        format: Format,
+    },
+    Url {
+        uri: Uri,
+        headers: HeaderMap,
+        format: Format,
+    },
+}
+
+impl From<CredentialSource> for Source {
+    fn from(source: CredentialSource) -> Self {
+        match source {
+            CredentialSource::File { file, format } => Self::File { path: file, format },
+            CredentialSource::Url {
+                url,
+                headers,
+                format,
+            } => Self::Url {
+                uri: Uri::from_maybe_shared(url).expect("credential source url is validated"),
+                headers: headers
+                    .into_iter()
+                    .map(|(k, v)| {
+                        (
+                            HeaderName::try_from(k).expect("header name is validated"),
+                            HeaderValue::try_from(v).expect("header value is validated"),
+                        )
+                    })
+                    .collect(),
+                format,
+            },
+            CredentialSource::Aws { .. } | CredentialSource::Executable { .. } => {
+                unreachable!("credential source is handled by its own fetcher")
+            }
+        }
+    }
+}
+
+// https://cloud.google.com/iam/docs/workload-identity-federation
+pub struct ExternalAccount {
+    sts: Sts,
+    // Reads the subject token from the credential source, which may be a local http endpoint.
+    inner: Client,
+    source: Source,
+}
+
+impl ExternalAccount {
+    pub(crate) fn new(ea: credentials::ExternalAccount, connect_timeout: Duration) -> Self {
+        Self {
+            sts: Sts::new(&ea, connect_timeout),
+            inner: Client::https_or_http(connect_timeout),
+            source: ea.credential_source.into(),
+        }
+    }
+
+    fn subject_token(&self) -> BoxFuture<'static, auth::Result<String>> {
+        match self.source {
+            Source::File {
+                ref path,
+                ref format,
+            } => {
+                let token = fs::read(path)
+                    .map_err(auth::Error::SubjectTokenFile)
+                    .and_then(|buf| parse_subject_token(format, &buf));
+                Box::pin(futures_util::future::ready(token))
+            }
+            Source::Url {
+                ref uri,
+                ref headers,
+                ref format,
+            } => {
+                let format = format.clone();
+                let req = self.inner.get(uri, headers);
+                let fut = self
+                    .inner
+                    .send_bytes(req)
+                    .and_then(move |buf: Bytes| async move { parse_subject_token(&format, &buf) });
+                Box::pin(fut)
+            }
+        }
+    }
+}
+
+fn parse_subject_token(format: &Format, buf: &[u8]) -> auth::Result<String> {
+    let token = match format {
+        Format::Text => String::from_utf8(buf.to_vec())
+            .map_err(|err| auth::Error::SubjectTokenFormat(err.to_string()))?,
+        Format::Json {
+            subject_token_field_name,
+        } => {
+            let value = serde_json::from_slice::<serde_json::Value>(buf)
+                .map_err(auth::Error::JsonDeserialize)?;
+            match value.get(subject_token_field_name) {
+                Some(serde_json::Value::String(s)) => s.clone(),
+                _ => {
+                    return Err(auth::Error::SubjectTokenFormat(format!(
+                        "missing string field `{}`",
+                        subject_token_field_name
+                    )))
+                }
+            }
+        }
+    };
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(auth::Error::SubjectTokenFormat(
+            "empty subject token".into(),
+        ));
+    }
+    Ok(token.to_owned())
+}
+
+impl fmt::Debug for ExternalAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExternalAccount").finish()
+    }
+}
+
+impl token::Fetcher for ExternalAccount {
+    fn fetch(&self) -> token::ResponseFuture {
+        self.sts.exchange(self.subject_token())
+    }
+}
+
+/// Exchanges a subject token for a Google access token at the Security Token Service, only over
+/// https since the subject token is a credential.
+// https://cloud.google.com/iam/docs/reference/sts/rest/v1/TopLevel/token
+pub(super) struct Sts {
+    pub(super) inner: Client,
+    token_url: Uri,
+    audience: String,
+    subject_token_type: String,
+    scopes: String,
+}
+
+impl Sts {
+    pub(super) fn new(ea: &credentials::ExternalAccount, connect_timeout: Duration) -> Self {
+        Self {
+            inner: Client::new(connect_timeout),
+            token_url: Uri::from_maybe_shared(ea.token_url.clone())
+                .expect("token url is validated"),
+            audience: ea.audience.clone(),
+            subject_token_type: ea.subject_token_type.clone(),
+            scopes: ea.scopes.join(" "),
+        }
+    }
+
+    pub(super) fn audience(&self) -> &str {
+        &self.audience
+    }
+
+    pub(super) fn subject_token_type(&self) -> &str {
+        &self.subject_token_type
+    }
+
+    pub(super) fn exchange(
+        &self,
+        subject_token: BoxFuture<'static, auth::Result<String>>,
+    ) -> token::ResponseFuture {
+        let inner = self.inner.clone();
+        let token_url = self.token_url.clone();
+        let audience = self.audience.clone();
+        let subject_token_type = self.subject_token_type.clone();
+        let scopes = self.scopes.clone();
+        Box::pin(async move {
+            let subject_token = subject_token.await?;
+            let req = inner.request(
+                &token_url,
+                &Payload {
+                    grant_type: "urn:ietf:params:oauth:grant-type:token-exchange",
+                    audience: &audience,
+                    scope: &scopes,
+                    requested_token_type: "urn:ietf:params:oauth:token-type:access_token",
+                    subject_token: &subject_token,
+                    subject_token_type: &subject_token_type,
+                },
+            );
+            inner.send(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_subject_token() {
+        assert_eq!(
+            parse_subject_token(&Format::Text, b"subject-token\n").unwrap(),
+            "subject-token"
+        );
+
+        let format = Format::Json {
+            subject_token_field_name: "access_token".into(),
+        };
+        assert_eq!(
+            parse_subject_token(&format, br#"{"access_token": "subject-token"}"#).unwrap(),
+            "subject-token"
+        );
+        assert!(matches!(
+            parse_subject_token(&format, br#"{"id_token": "subject-token"}"#),
+            Err(auth::Error::SubjectTokenFormat(_))
+        ));
+        assert!(matches!(
+            parse_subject_token(&Format::Text, b""),
+            Err(auth::Error::SubjectTokenFormat(_))
+        ));
+    }
+}
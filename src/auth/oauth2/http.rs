@@ -1,24 +1,46 @@
-use std::future::Future;
+use std::{error::Error as _, future::Future, io, time::Duration};
 
+use bytes::Bytes;
 use hyper::{
-    body::aggregate,
+    body::to_bytes,
     client::HttpConnector,
-    header::{HeaderValue, CONTENT_TYPE, USER_AGENT},
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE, USER_AGENT},
     Body, Method, Request, StatusCode, Uri,
 };
 use hyper_rustls::{builderstates::WantsSchemes, HttpsConnector, HttpsConnectorBuilder};
 
 use crate::auth;
 
-pub(super) struct Client {
+/// The default timeout of establishing connections to token endpoints.
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub(crate) struct Client {
     inner: hyper::Client<HttpsConnector<HttpConnector>, Body>,
+    connect_timeout: Duration,
     user_agent: HeaderValue,
     content_type: HeaderValue,
 }
 
 impl Client {
-    pub fn new() -> Client {
-        let https = connection_builder().https_only().enable_http2().build();
+    pub fn new(connect_timeout: Duration) -> Client {
+        let https = connection_builder()
+            .https_only()
+            .enable_http2()
+            .wrap_connector(connector(connect_timeout));
+        Self::with_connector(https, connect_timeout)
+    }
+
+    /// Some credential sources (e.g. local metadata endpoints) are only served over plain http.
+    pub fn https_or_http(connect_timeout: Duration) -> Client {
+        let https = connection_builder()
+            .https_or_http()
+            .enable_http2()
+            .wrap_connector(connector(connect_timeout));
+        Self::with_connector(https, connect_timeout)
+    }
+
+    fn with_connector(https: HttpsConnector<HttpConnector>, connect_timeout: Duration) -> Client {
         let user_agent = concat!(
             "github.com/mechiru/",
             env!("CARGO_PKG_NAME"),
@@ -27,6 +49,7 @@ impl Client {
         );
         Self {
             inner: hyper::Client::builder().build(https),
+            connect_timeout,
             user_agent: HeaderValue::from_static(user_agent),
             content_type: HeaderValue::from_static("application/x-www-form-urlencoded"),
         }
@@ -44,6 +67,35 @@ impl Client {
         req.body(body).unwrap()
     }
 
+    pub fn json_request<T>(&self, uri: &Uri, authorization: HeaderValue, body: &T) -> Request<Body>
+    where
+        T: serde::Serialize,
+    {
+        let mut req = Request::builder().uri(uri).method(Method::POST);
+        let headers = req.headers_mut().unwrap();
+        headers.insert(USER_AGENT, self.user_agent.clone());
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(AUTHORIZATION, authorization);
+        let body = Body::from(serde_json::to_vec(body).unwrap());
+        req.body(body).unwrap()
+    }
+
+    pub fn get(&self, uri: &Uri, headers: &HeaderMap) -> Request<Body> {
+        self.empty(Method::GET, uri, headers)
+    }
+
+    pub fn put(&self, uri: &Uri, headers: &HeaderMap) -> Request<Body> {
+        self.empty(Method::PUT, uri, headers)
+    }
+
+    fn empty(&self, method: Method, uri: &Uri, headers: &HeaderMap) -> Request<Body> {
+        let mut req = Request::builder().uri(uri).method(method);
+        let h = req.headers_mut().unwrap();
+        h.insert(USER_AGENT, self.user_agent.clone());
+        h.extend(headers.clone());
+        req.body(Body::empty()).unwrap()
+    }
+
     pub fn send<T>(
         &self,
         req: Request<Body>,
@@ -51,22 +103,60 @@ impl Client {
     where
         T: serde::de::DeserializeOwned,
     {
-        let fut = self.inner.request(req);
+        let fut = self.send_bytes(req);
         async {
-            use bytes::Buf as _;
+            let buf = fut.await?;
+            serde_json::from_slice(&buf).map_err(auth::Error::JsonDeserialize)
+        }
+    }
 
-            let (parts, body) = fut.await?.into_parts();
+    pub fn send_bytes(
+        &self,
+        req: Request<Body>,
+    ) -> impl Future<Output = auth::Result<Bytes>> + Send + 'static {
+        let (fut, connect_timeout) = (self.inner.request(req), self.connect_timeout);
+        async move {
+            let (parts, body) = fut
+                .await
+                .map_err(|err| {
+                    if is_connect_timeout(&err) {
+                        auth::Error::Timeout(connect_timeout)
+                    } else {
+                        auth::Error::Http(err)
+                    }
+                })?
+                .into_parts();
             match parts.status {
-                StatusCode::OK => {
-                    let buf = aggregate(body).await?;
-                    serde_json::from_reader(buf.reader()).map_err(auth::Error::JsonDeserialize)
-                }
-                _ => Err(auth::Error::StatusCode((parts, body))),
+                StatusCode::OK => Ok(to_bytes(body).await?),
+                _ => Err(auth::Error::StatusCode(Box::new((parts, body)))),
             }
         }
     }
 }
 
+fn connector(connect_timeout: Duration) -> HttpConnector {
+    let mut http = HttpConnector::new();
+    // The scheme is checked by the https connector.
+    http.enforce_http(false);
+    http.set_connect_timeout(Some(connect_timeout));
+    http
+}
+
+/// Reports whether establishing the connection timed out.
+fn is_connect_timeout(err: &hyper::Error) -> bool {
+    if !err.is_connect() {
+        return false;
+    }
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return err.kind() == io::ErrorKind::TimedOut;
+        }
+        source = err.source();
+    }
+    false
+}
+
 #[cfg(feature = "native-certs")]
 fn connection_builder() -> HttpsConnectorBuilder<WantsSchemes> {
     HttpsConnectorBuilder::new().with_native_roots()
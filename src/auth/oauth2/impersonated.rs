@@ -0,0 +1,232 @@
+use std::{
+    convert::TryFrom as _,
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use hyper::Uri;
+use serde::de::{self, Deserialize as _, Deserializer};
+
+use crate::{
+    auth::oauth2::{datetime::parse_rfc3339, http::Client, token},
+    credentials,
+};
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AccessTokenRequest<'a> {
+    delegates: &'a [String],
+    scope: &'a [String],
+    lifetime: &'a str,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AccessTokenResponse {
+    access_token: String,
+    #[serde(deserialize_with = "deserialize_rfc3339")]
+    expire_time: u64,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct IdTokenRequest<'a> {
+    delegates: &'a [String],
+    audience: &'a str,
+    include_email: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct IdTokenResponse {
+    token: String,
+}
+
+fn deserialize_rfc3339<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_rfc3339(&s).ok_or_else(|| de::Error::custom(format!("invalid timestamp: {}", s)))
+}
+
+struct Config {
+    inner: Client,
+    access_token_uri: Uri,
+    id_token_uri: Uri,
+    delegates: Vec<String>,
+    scopes: Vec<String>,
+    audience: Option<String>,
+    lifetime: String,
+}
+
+// https://cloud.google.com/iam/docs/reference/credentials/rest
+pub struct Impersonated {
+    source: Box<dyn token::Fetcher>,
+    config: Arc<Config>,
+}
+
+impl Impersonated {
+    pub(crate) fn new(
+        imp: credentials::Impersonated,
+        source: Box<dyn token::Fetcher>,
+        connect_timeout: Duration,
+    ) -> Self {
+        let url = imp.service_account_impersonation_url;
+        let id_token_url = url.replace(":generateAccessToken", ":generateIdToken");
+        Self {
+            source,
+            config: Arc::new(Config {
+                inner: Client::new(connect_timeout),
+                access_token_uri: Uri::from_maybe_shared(url)
+                    .expect("impersonation url is validated"),
+                id_token_uri: Uri::from_maybe_shared(id_token_url)
+                    .expect("impersonation url is validated"),
+                delegates: imp.delegates,
+                scopes: imp.scopes,
+                audience: imp.audience,
+                lifetime: format!("{}s", imp.lifetime.as_secs()),
+            }),
+        }
+    }
+}
+
+impl fmt::Debug for Impersonated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Impersonated")
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl token::Fetcher for Impersonated {
+    fn fetch(&self) -> token::ResponseFuture {
+        let source = self.source.fetch();
+        let config = self.config.clone();
+        Box::pin(async move {
+            let source = token::Token::try_from(source.await?)?;
+            let inner = &config.inner;
+            match config.audience {
+                Some(ref audience) => {
+                    let req = inner.json_request(
+                        &config.id_token_uri,
+                        source.value,
+                        &IdTokenRequest {
+                            delegates: &config.delegates,
+                            audience,
+                            include_email: true,
+                        },
+                    );
+                    let resp: IdTokenResponse = inner.send(req).await?;
+                    Ok(token::Response::IdToken {
+                        id_token: resp.token,
+                    })
+                }
+                None => {
+                    let req = inner.json_request(
+                        &config.access_token_uri,
+                        source.value,
+                        &AccessTokenRequest {
+                            delegates: &config.delegates,
+                            scope: &config.scopes,
+                            lifetime: &config.lifetime,
+                        },
+                    );
+                    let resp: AccessTokenResponse = inner.send(req).await?;
+                    let now = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
+                    Ok(token::Response::AccessToken {
+                        token_type: "Bearer".into(),
+                        access_token: resp.access_token,
+                        expires_in: resp.expire_time.saturating_sub(now),
+                    })
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::Response;
+
+    use super::*;
+    use crate::{
+        auth::oauth2::{http::CONNECT_TIMEOUT, token::Fetcher},
+        testing, Credentials,
+    };
+
+    #[derive(Debug)]
+    struct Source;
+
+    impl Fetcher for Source {
+        fn fetch(&self) -> token::ResponseFuture {
+            Box::pin(async {
+                Ok(token::Response::AccessToken {
+                    token_type: "Bearer".into(),
+                    access_token: "source".into(),
+                    expires_in: 3600,
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let addr = testing::serve(|parts, body| {
+            assert_eq!(parts.headers["authorization"], "Bearer source");
+            let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+            assert_eq!(body["delegates"][0], "projects/-/serviceAccounts/delegate");
+            let resp = match parts.uri.path() {
+                "/v1/projects/-/serviceAccounts/target:generateAccessToken" => {
+                    assert_eq!(body["scope"][0], "scope");
+                    assert_eq!(body["lifetime"], "600s");
+                    r#"{"accessToken": "impersonated", "expireTime": "2100-01-01T00:00:00Z"}"#
+                }
+                "/v1/projects/-/serviceAccounts/target:generateIdToken" => {
+                    assert_eq!(body["audience"], "audience");
+                    r#"{"token": "id-token"}"#
+                }
+                _ => unreachable!(),
+            };
+            Response::new(resp.into())
+        });
+
+        let impersonated = |audience: Option<&str>| {
+            let mut impersonated = Impersonated::new(
+                credentials::Impersonated {
+                    source: Credentials::None,
+                    service_account_impersonation_url: format!(
+                        "http://{}/v1/projects/-/serviceAccounts/target:generateAccessToken",
+                        addr
+                    ),
+                    delegates: vec!["projects/-/serviceAccounts/delegate".into()],
+                    scopes: vec!["scope".into()],
+                    audience: audience.map(Into::into),
+                    lifetime: Duration::from_secs(600),
+                    quota_project_id: None,
+                    universe_domain: None,
+                },
+                Box::new(Source),
+                CONNECT_TIMEOUT,
+            );
+            // The local endpoint is only served over plain http.
+            Arc::get_mut(&mut impersonated.config).unwrap().inner =
+                Client::https_or_http(CONNECT_TIMEOUT);
+            impersonated
+        };
+
+        match impersonated(None).fetch().await.unwrap() {
+            token::Response::AccessToken {
+                access_token,
+                expires_in,
+                ..
+            } => {
+                assert_eq!(access_token, "impersonated");
+                assert!(expires_in > 3600);
+            }
+            resp => panic!("unexpected response: {:?}", resp),
+        }
+
+        match impersonated(Some("audience")).fetch().await.unwrap() {
+            token::Response::IdToken { id_token } => assert_eq!(id_token, "id-token"),
+            resp => panic!("unexpected response: {:?}", resp),
+        }
+    }
+}
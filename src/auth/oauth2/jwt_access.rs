@@ -0,0 +1,212 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use hyper::{header::AUTHORIZATION, http::HeaderValue, Request, Uri};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use parking_lot::Mutex;
+use tracing::trace;
+
+use crate::{
+    auth::{
+        self,
+        oauth2::{
+            service_account::{header, issued_at},
+            token::Token,
+        },
+    },
+    credentials,
+};
+
+#[derive(serde::Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    sub: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aud: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<&'a str>,
+    iat: u64,
+    exp: u64,
+}
+
+// Requests may go to arbitrary origins, so bound the number of cached tokens.
+const MAX_CACHED_ORIGINS: usize = 64;
+
+struct Inner {
+    header: Header,
+    private_key: EncodingKey,
+    client_email: String,
+    scopes: String,
+    // Self-signed JWTs are bound to an audience, so keep one per API origin.
+    cache: Mutex<HashMap<String, Token>>,
+}
+
+/// Authorizes requests with self-signed JWTs instead of exchanging them for access tokens.
+// https://google.aip.dev/auth/4111
+#[derive(Clone)]
+pub(crate) struct JwtAccess {
+    inner: Arc<Inner>,
+}
+
+impl JwtAccess {
+    pub fn new(sa: credentials::ServiceAccount) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                header: header("JWT", sa.private_key_id),
+                private_key: sa.private_key.key,
+                client_email: sa.client_email,
+                scopes: sa.scopes.join(" "),
+                cache: Default::default(),
+            }),
+        }
+    }
+
+    pub fn add_header<B>(&self, mut req: Request<B>) -> auth::Result<Request<B>> {
+        // Without an origin (e.g. tonic requests before the channel adds it),
+        // fall back to a JWT carrying the scope claim.
+        let audience = origin(req.uri());
+        let key = audience.clone().unwrap_or_default();
+        let cached = match self.inner.cache.lock().get(&key) {
+            Some(token) if !token.expired(Instant::now()) => Some(token.value.clone()),
+            _ => None,
+        };
+        let value = match cached {
+            Some(value) => value,
+            None => {
+                // Sign without holding the lock, so requests to other origins are not blocked.
+                let token = self.mint(audience.as_deref())?;
+                let value = token.value.clone();
+                let mut cache = self.inner.cache.lock();
+                if cache.len() >= MAX_CACHED_ORIGINS && !cache.contains_key(&key) {
+                    evict(&mut cache);
+                }
+                cache.insert(key, token);
+                value
+            }
+        };
+        req.headers_mut().insert(AUTHORIZATION, value);
+        Ok(req)
+    }
+
+    fn mint(&self, audience: Option<&str>) -> auth::Result<Token> {
+        const EXPIRE: u64 = 60 * 60;
+
+        let iat = issued_at();
+        let claims = Claims {
+            iss: &self.inner.client_email,
+            sub: &self.inner.client_email,
+            aud: audience,
+            scope: match audience {
+                Some(_) => None,
+                None => Some(&self.inner.scopes),
+            },
+            iat,
+            exp: iat + EXPIRE,
+        };
+        let jwt = encode(&self.inner.header, &claims, &self.inner.private_key)?;
+        trace!("minted self-signed jwt: aud={:?}", audience);
+
+        let value = HeaderValue::from_str(&format!("Bearer {}", jwt)).unwrap();
+        // `iat` is set 10 seconds in the past.
+        let expiry = Instant::now() + Duration::from_secs(EXPIRE - 10);
+        Ok(Token::new(value, expiry))
+    }
+}
+
+// Drops expired tokens, or an arbitrary one if all of them are still usable.
+fn evict(cache: &mut HashMap<String, Token>) {
+    let now = Instant::now();
+    cache.retain(|_, token| !token.expired(now));
+    if cache.len() >= MAX_CACHED_ORIGINS {
+        if let Some(key) = cache.keys().next().cloned() {
+            cache.remove(&key);
+        }
+    }
+}
+
+fn origin(uri: &Uri) -> Option<String> {
+    match (uri.scheme_str(), uri.authority()) {
+        (Some(scheme), Some(authority)) => Some(format!("{}://{}/", scheme, authority.host())),
+        _ => None,
+    }
+}
+
+impl fmt::Debug for JwtAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtAccess").finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryFrom;
+
+    use jsonwebtoken::{decode, DecodingKey, Validation};
+
+    use super::*;
+    use crate::testing::PRIVATE_KEY;
+
+    fn claims(req: &Request<()>) -> serde_json::Value {
+        let value = req.headers()[AUTHORIZATION].to_str().unwrap();
+        let jwt = value.strip_prefix("Bearer ").unwrap();
+        let mut validation = Validation::default();
+        validation.insecure_disable_signature_validation();
+        validation.validate_exp = false;
+        validation.required_spec_claims.clear();
+        decode::<serde_json::Value>(jwt, &DecodingKey::from_secret(&[]), &validation)
+            .unwrap()
+            .claims
+    }
+
+    #[test]
+    fn test_add_header() {
+        let jwt = JwtAccess::new(credentials::ServiceAccount {
+            scopes: vec!["scope1".into(), "scope2".into()],
+            audience: None,
+            client_email: "sa@project.iam.gserviceaccount.com".into(),
+            private_key_id: "key-id".into(),
+            private_key: credentials::PrivateKey::try_from(PRIVATE_KEY.to_owned()).unwrap(),
+            token_uri: "https://oauth2.googleapis.com/token".into(),
+            quota_project_id: None,
+            universe_domain: None,
+            self_signed_jwt: true,
+            subject: None,
+        });
+
+        let req = Request::get("https://pubsub.googleapis.com/v1/projects/p/topics")
+            .body(())
+            .unwrap();
+        let req = jwt.add_header(req).unwrap();
+        let c = claims(&req);
+        assert_eq!(c["aud"], "https://pubsub.googleapis.com/");
+        assert_eq!(c["iss"], "sa@project.iam.gserviceaccount.com");
+        assert_eq!(c["sub"], "sa@project.iam.gserviceaccount.com");
+        assert!(c.get("scope").is_none());
+
+        // The token for an origin is cached.
+        let again = Request::get("https://pubsub.googleapis.com:443/v1/other")
+            .body(())
+            .unwrap();
+        let again = jwt.add_header(again).unwrap();
+        assert_eq!(req.headers()[AUTHORIZATION], again.headers()[AUTHORIZATION]);
+
+        let req = Request::get("/google.pubsub.v1.Publisher/ListTopics")
+            .body(())
+            .unwrap();
+        let c = claims(&jwt.add_header(req).unwrap());
+        assert!(c.get("aud").is_none());
+        assert_eq!(c["scope"], "scope1 scope2");
+
+        for i in 0..2 * MAX_CACHED_ORIGINS {
+            let req = Request::get(format!("https://api{}.googleapis.com/", i))
+                .body(())
+                .unwrap();
+            jwt.add_header(req).unwrap();
+        }
+        assert_eq!(jwt.inner.cache.lock().len(), MAX_CACHED_ORIGINS);
+    }
+}
@@ -1,11 +1,11 @@
 use std::{fmt, str::FromStr as _};
 
-use futures_util::TryFutureExt as _;
-use hyper::{client::HttpConnector, http::uri::PathAndQuery, Body};
+use hyper::http::uri::PathAndQuery;
 
 use crate::{
-    auth::{self, oauth2::token},
-    credentials,
+    auth::oauth2::token,
+    credentials::{self, IdentityTokenFormat},
+    metadata,
 };
 
 use super::token::Response;
@@ -18,10 +18,13 @@ struct Query<'a> {
 #[derive(serde::Serialize)]
 struct AudienceQuery<'a> {
     audience: &'a str,
+    format: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    licenses: Option<&'a str>,
 }
 
 pub struct Metadata {
-    inner: gcemeta::Client<HttpConnector, Body>,
+    inner: metadata::Client,
     path_and_query: PathAndQuery,
     is_service_to_service: bool,
 }
@@ -29,7 +32,13 @@ pub struct Metadata {
 impl Metadata {
     pub(crate) fn new(meta: Box<credentials::Metadata>) -> Self {
         let is_service_to_service = meta.audience.is_some();
-        let path_and_query = path_and_query(meta.account, meta.scopes, meta.audience);
+        let path_and_query = path_and_query(
+            meta.account,
+            meta.scopes,
+            meta.audience,
+            meta.identity_token_format,
+            meta.identity_token_licenses,
+        );
         let path_and_query = PathAndQuery::from_str(&path_and_query).unwrap();
         Self {
             inner: meta.client,
@@ -43,6 +52,8 @@ fn path_and_query(
     account: Option<String>,
     scopes: Vec<String>,
     audience: Option<String>,
+    format: IdentityTokenFormat,
+    licenses: bool,
 ) -> String {
     let mut path_and_query = "/computeMetadata/v1/instance/service-accounts/".to_owned();
     path_and_query.push_str(account.as_ref().map_or("default", String::as_str));
@@ -55,6 +66,9 @@ fn path_and_query(
         path_and_query.push('?');
         let query = AudienceQuery {
             audience: aud.as_str(),
+            format: format.as_str(),
+            // Licenses are only included in tokens of the full format.
+            licenses: (licenses && format == IdentityTokenFormat::Full).then_some("TRUE"),
         };
         path_and_query.push_str(&serde_urlencoded::to_string(&query).unwrap());
     } else if !scopes.is_empty() {
@@ -76,20 +90,17 @@ impl fmt::Debug for Metadata {
 impl token::Fetcher for Metadata {
     fn fetch(&self) -> token::ResponseFuture {
         // Already checked that this process is running on GCE.
-        if self.is_service_to_service {
-            let fut = self
-                .inner
-                .get(self.path_and_query.clone(), true)
-                .map_ok(|s| Response::IdToken { id_token: s })
-                .map_err(auth::Error::Gcemeta);
-            Box::pin(fut)
-        } else {
-            let fut = self
-                .inner
-                .get_as(self.path_and_query.clone())
-                .map_err(auth::Error::Gcemeta);
-            Box::pin(fut)
-        }
+        let inner = self.inner.clone();
+        let path_and_query = self.path_and_query.clone();
+        let is_service_to_service = self.is_service_to_service;
+        Box::pin(async move {
+            if is_service_to_service {
+                let id_token = inner.get(path_and_query).await?;
+                Ok(Response::IdToken { id_token })
+            } else {
+                Ok(inner.get_as(path_and_query).await?)
+            }
+        })
     }
 }
 
@@ -100,23 +111,34 @@ mod test {
     #[test]
     fn test_path_and_query() {
         assert_eq!(
-            &path_and_query(None, vec![], None),
+            &path_and_query(None, vec![], None, IdentityTokenFormat::Standard, false),
             "/computeMetadata/v1/instance/service-accounts/default/token"
         );
 
         assert_eq!(
-            &path_and_query(None, vec!["https://www.googleapis.com/auth/cloud-platform".to_owned()], None),
+            &path_and_query(None, vec!["https://www.googleapis.com/auth/cloud-platform".to_owned()], None, IdentityTokenFormat::Standard, false),
             "/computeMetadata/v1/instance/service-accounts/default/token?scopes=https%3A%2F%2Fwww.googleapis.com%2Fauth%2Fcloud-platform"
         );
 
         assert_eq!(
-            &path_and_query(None, vec!["scope1".to_owned(), "scope2".to_owned()], None),
+            &path_and_query(
+                None,
+                vec!["scope1".to_owned(), "scope2".to_owned()],
+                None,
+                IdentityTokenFormat::Standard,
+                false
+            ),
             "/computeMetadata/v1/instance/service-accounts/default/token?scopes=scope1%2Cscope2"
         );
 
         assert_eq!(
-            &path_and_query(None, vec![], Some("https://some-service.url".to_owned())),
-            "/computeMetadata/v1/instance/service-accounts/default/identity?audience=https%3A%2F%2Fsome-service.url"
+            &path_and_query(None, vec![], Some("https://some-service.url".to_owned()), IdentityTokenFormat::Standard, true),
+            "/computeMetadata/v1/instance/service-accounts/default/identity?audience=https%3A%2F%2Fsome-service.url&format=standard"
+        );
+
+        assert_eq!(
+            &path_and_query(None, vec![], Some("https://some-service.url".to_owned()), IdentityTokenFormat::Full, true),
+            "/computeMetadata/v1/instance/service-accounts/default/identity?audience=https%3A%2F%2Fsome-service.url&format=full&licenses=TRUE"
         )
     }
 }
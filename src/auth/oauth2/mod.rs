@@ -1,48 +1,112 @@
 use std::{
+    collections::HashMap,
     convert::TryFrom as _,
     fmt,
     sync::Arc,
-    task::{self, Poll},
-    time::Instant,
+    task::{self, Poll, Waker},
+    time::{Duration, Instant},
 };
 
+use futures_util::future::{select, Either};
 use hyper::{
     header::{self, AUTHORIZATION},
     Request,
 };
 use parking_lot::RwLock;
-use tracing::{info, trace};
+use tracing::{info, trace, warn};
 
-use crate::{auth, sync::RefGuard};
+use crate::{
+    auth::{self, Subject},
+    runtime::Timer,
+    sync::{RefGuard, Wakers},
+};
 
-mod http;
+mod datetime;
+pub(crate) mod http;
+pub(crate) mod id_token;
 pub(super) mod token;
 
+mod access_token;
+mod aws;
+mod executable;
+mod external_account;
+mod impersonated;
+mod jwt_access;
 mod metadata;
+mod refresher;
 mod service_account;
 mod user;
 
+pub use access_token::AccessToken;
+pub use aws::Aws;
+pub use executable::Executable;
+pub use external_account::ExternalAccount;
+pub use id_token::{IdTokenClaims, InstanceIdentity};
+pub use impersonated::Impersonated;
+pub(crate) use jwt_access::JwtAccess;
 pub use metadata::Metadata;
 pub use service_account::ServiceAccount;
 pub use user::User;
 
+/// Stands for a cooldown too long to be added to an `Instant`.
+const FOREVER: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
 #[derive(Clone)]
 pub(super) struct Oauth2 {
     inner: Arc<RwLock<Inner>>,
+    // Tokens for the registered subjects selected per request, see `Subject`.
+    subjects: Arc<HashMap<String, Arc<RwLock<Inner>>>>,
+    // Shared by all clones, so that the background task stops with the last one.
+    refresher: Option<Arc<refresher::Refresher>>,
 }
 
 impl Oauth2 {
-    pub fn new(fetcher: Box<dyn token::Fetcher>, max_retry: u8) -> Self {
+    pub fn new(fetcher: Box<dyn token::Fetcher>, config: &auth::Config) -> Self {
+        // Requests acting as subjects that the credentials do not support fail.
+        let subjects = config
+            .subjects
+            .iter()
+            .filter_map(|subject| {
+                let fetcher = fetcher.with_subject(subject)?;
+                let inner = Inner::new(fetcher, config.into(), config.timer.clone());
+                Some((subject.clone(), Arc::new(RwLock::new(inner))))
+            })
+            .collect();
+        let inner = Arc::new(RwLock::new(Inner::new(
+            fetcher,
+            config.into(),
+            config.timer.clone(),
+        )));
+        let refresher = config.background_refresh.as_ref().map(|(executor, timer)| {
+            Arc::new(refresher::Refresher::spawn(
+                inner.clone(),
+                &**executor,
+                timer.clone(),
+            ))
+        });
         Self {
-            inner: Arc::new(RwLock::new(Inner {
-                state: State::NotFetched,
-                fetcher,
-                max_retry,
-            })),
+            inner,
+            subjects: Arc::new(subjects),
+            refresher,
         }
     }
 
+    pub fn status(&self) -> RefreshStatus {
+        self.inner.read().status.clone()
+    }
+
     pub fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<auth::Result<()>> {
+        // The tokens of subjects are fetched along with the default one, but never hold up
+        // readiness: their fetches and errors only affect the requests acting as them.
+        for (subject, inner) in self.subjects.iter() {
+            if inner.read().can_skip_poll_ready() {
+                continue;
+            }
+            if let Poll::Ready(Err(err)) = inner.write().poll_ready(cx) {
+                trace!("token fetching failed for subject {}: {}", subject, err)
+            }
+        }
+
         if self.inner.read().can_skip_poll_ready() {
             return Poll::Ready(Ok(()));
         }
@@ -50,10 +114,18 @@ impl Oauth2 {
     }
 
     #[inline]
-    pub fn add_header<B>(&self, mut req: Request<B>) -> Request<B> {
-        req.headers_mut()
-            .insert(AUTHORIZATION, self.inner.read().value());
-        req
+    pub fn call<B>(&self, mut req: Request<B>) -> auth::Result<Request<B>> {
+        let value = match req.extensions().get::<Subject>() {
+            Some(Subject(subject)) => self
+                .subjects
+                .get(subject)
+                .ok_or_else(|| auth::Error::UnsupportedSubject(subject.clone()))?
+                .read()
+                .token()?,
+            None => self.inner.read().value(),
+        };
+        req.headers_mut().insert(AUTHORIZATION, value);
+        Ok(req)
     }
 }
 
@@ -61,44 +133,185 @@ impl fmt::Debug for Oauth2 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Oauth2")
             .field("inner", &self.inner)
+            .field("subjects", &self.subjects.keys())
+            .field("background_refresh", &self.refresher.is_some())
             .finish()
     }
 }
 
+/// The outcome of token refreshes, see [`GoogleAuthz::refresh_status`](crate::GoogleAuthz::refresh_status).
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct RefreshStatus {
+    /// When the current token was fetched.
+    pub last_refresh: Option<Instant>,
+    /// When the current token expires.
+    pub expiry: Option<Instant>,
+    /// The error of the last refresh, if it failed after all retries.
+    pub last_error: Option<String>,
+    /// The number of refreshes that failed after all retries since the last success.
+    pub consecutive_failures: u32,
+}
+
+impl RefreshStatus {
+    fn succeeded(&mut self, token: &token::Token) {
+        self.last_refresh = Some(Instant::now());
+        self.expiry = Some(token.expiry);
+        self.last_error = None;
+        self.consecutive_failures = 0;
+    }
+
+    fn failed(&mut self, err: &auth::Error) {
+        self.last_error = Some(err.to_string());
+        self.consecutive_failures += 1;
+    }
+}
+
+/// How tokens are fetched, see [`auth::Config`].
+#[derive(Clone, Copy, Debug)]
+struct Policy {
+    max_retry: u8,
+    retry: token::RetryPolicy,
+    fetch_timeout: Duration,
+    refresh: token::RefreshPolicy,
+    cooldown: Duration,
+    // Whether the last token is used while it is valid, although a refresh failed.
+    keep_valid_token: bool,
+}
+
+impl From<&auth::Config> for Policy {
+    fn from(config: &auth::Config) -> Self {
+        Self {
+            max_retry: config.max_retry,
+            retry: config.retry,
+            fetch_timeout: config.fetch_timeout,
+            refresh: config.refresh,
+            cooldown: config.retry_cooldown,
+            keep_valid_token: config.keep_valid_token,
+        }
+    }
+}
+
 struct Inner {
     state: State,
-    fetcher: Box<dyn token::Fetcher>,
-    max_retry: u8,
+    // Shared with the futures of retries, which fetch once their backoff is over.
+    fetcher: Arc<dyn token::Fetcher>,
+    policy: Policy,
+    timer: Option<Arc<dyn Timer>>,
+    status: RefreshStatus,
+    // Every clone waiting for the fetch, which is polled with `waker` so that all of them are
+    // woken whichever polled it last.
+    wakers: Arc<Wakers>,
+    waker: Waker,
 }
 
 impl Inner {
+    fn new(
+        fetcher: Box<dyn token::Fetcher>,
+        policy: Policy,
+        timer: Option<Arc<dyn Timer>>,
+    ) -> Self {
+        let wakers = Arc::new(Wakers::default());
+        Self {
+            state: State::NotFetched,
+            fetcher: fetcher.into(),
+            policy,
+            timer,
+            status: RefreshStatus::default(),
+            waker: futures_util::task::waker(wakers.clone()),
+            wakers,
+        }
+    }
+
+    /// Returns how long the current token is used before it is refreshed, or how long the
+    /// cooldown after a failure lasts.
+    fn refresh_in(&self, now: Instant) -> Option<Duration> {
+        match self.state {
+            State::Fetched { ref current } => {
+                Some(current.refresh_at.saturating_duration_since(now))
+            }
+            State::Failed { until, .. } => Some(until.saturating_duration_since(now)),
+            _ => None,
+        }
+    }
+
     #[inline]
     fn can_skip_poll_ready(&self) -> bool {
-        matches!(self.state, State::Fetched { ref current } if !current.expired(Instant::now()))
+        matches!(self.state, State::Fetched { ref current } if !current.needs_refresh(Instant::now()))
     }
 
+    /// Polls the fetch, and registers the waker of `cx` only if the caller has to wait for it.
     #[inline]
     fn poll_ready(&mut self, cx: &mut task::Context<'_>) -> Poll<auth::Result<()>> {
+        let wakers = self.wakers.clone();
+        wakers.poll(cx.waker(), || self.poll_usable())
+    }
+
+    #[inline]
+    fn poll_usable(&mut self) -> Poll<auth::Result<()>> {
+        match self.poll_fetch() {
+            // The last token is used until the new one arrives, as long as it can be used.
+            Poll::Pending => match self.state {
+                State::Refetching { ref last, .. } if !last.expired(Instant::now()) => {
+                    Poll::Ready(Ok(()))
+                }
+                _ => Poll::Pending,
+            },
+            Poll::Ready(Err(err)) if self.policy.keep_valid_token => match self.state {
+                State::Failed {
+                    last: Some(ref last),
+                    ..
+                } if !last.expired(Instant::now()) => {
+                    warn!(
+                        "token refresh failed, the current token is used until it expires: expiry={:?}, err={}",
+                        last.expiry, err
+                    );
+                    Poll::Ready(Ok(()))
+                }
+                _ => Poll::Ready(Err(err)),
+            },
+            poll => poll,
+        }
+    }
+
+    fn poll_fetch(&mut self) -> Poll<auth::Result<()>> {
         macro_rules! poll {
-            ($variant:ident, $future:expr, $attempts:ident) => {
-                poll!($variant, $future, $attempts,)
+            ($variant:ident, $future:expr, $attempts:ident, $started:ident, $last:expr) => {
+                poll!($variant, $future, $attempts, $started, $last,)
             };
-            ($variant:ident, $future:expr, $attempts:ident, $($field:ident),*) => {
-                match $future.get_mut().as_mut().poll(cx) {
+            ($variant:ident, $future:expr, $attempts:ident, $started:ident, $last:expr, $($field:ident),*) => {{
+                match $future.get_mut().as_mut().poll(&mut task::Context::from_waker(&self.waker)) {
                     Poll::Ready(resp) => match resp.and_then(token::Token::try_from) {
-                        Ok(token) => {
-                            trace!("fetched token: expiry={:?}", token.expiry);
+                        Ok(mut token) => {
+                            token.refresh_at = self.policy.refresh.refresh_at(Instant::now(), token.expiry, token::random());
+                            trace!("fetched token: expiry={:?}, refresh_at={:?}", token.expiry, token.refresh_at);
+                            self.status.succeeded(&token);
                             self.state = State::Fetched { current: token };
+                            self.waker.wake_by_ref();
                             break Poll::Ready(Ok(()));
                         }
                         Err(err) => {
-                            if $attempts > self.max_retry {
-                                break Poll::Ready(Err(err));
-                            }
-                            info!("an error occurred during token fetching: attempts={}, err={:?}", $attempts, err);
+                            let retry_in = match self.retry_in($attempts, $started, &err) {
+                                Some(retry_in) => retry_in,
+                                None => {
+                                    self.status.failed(&err);
+                                    // The future has completed, so the next poll starts over after a cooldown.
+                                    let cooldown = err.retry_after().map_or(self.policy.cooldown, |after| after.max(self.policy.cooldown));
+                                    info!("token fetching failed after {} attempts, cooling down for {:?}", $attempts, cooldown);
+                                    let now = Instant::now();
+                                    self.state = State::Failed {
+                                        last: $last,
+                                        until: now.checked_add(cooldown).unwrap_or_else(|| now + FOREVER),
+                                    };
+                                    self.waker.wake_by_ref();
+                                    break Poll::Ready(Err(err));
+                                }
+                            };
+                            info!("an error occurred during token fetching, retrying in {:?}: attempts={}, err={:?}", retry_in, $attempts, err);
                             self.state = State::$variant {
-                                future: RefGuard::new(self.fetcher.fetch()),
+                                future: RefGuard::new(self.fetch_after(retry_in)),
                                 attempts: $attempts + 1,
+                                started: $started,
                                 $(
                                     $field: $field.clone(),
                                 )*
@@ -107,7 +320,7 @@ impl Inner {
                     },
                     Poll::Pending => break Poll::Pending,
                 }
-            };
+            }};
         }
 
         loop {
@@ -115,29 +328,69 @@ impl Inner {
                 State::NotFetched => {
                     trace!("token is not fetched");
                     self.state = State::Fetching {
-                        future: RefGuard::new(self.fetcher.fetch()),
+                        future: RefGuard::new(self.fetch()),
                         attempts: 1,
+                        started: Instant::now(),
                     };
                 }
                 State::Fetching {
                     ref mut future,
                     attempts,
-                } => poll!(Fetching, future, attempts),
+                    started,
+                } => poll!(Fetching, future, attempts, started, None),
                 State::Refetching {
                     ref mut future,
                     attempts,
+                    started,
                     ref last,
                 } => {
-                    poll!(Refetching, future, attempts, last)
+                    poll!(
+                        Refetching,
+                        future,
+                        attempts,
+                        started,
+                        Some(last.clone()),
+                        last
+                    )
+                }
+                State::Failed { ref last, until } => {
+                    let now = Instant::now();
+                    if now < until {
+                        break match last {
+                            // The last token is used during the cooldown, as long as it can be used.
+                            Some(last) if !last.expired(now) && self.policy.keep_valid_token => {
+                                Poll::Ready(Ok(()))
+                            }
+                            _ => Poll::Ready(Err(auth::Error::RefreshCooldown {
+                                retry_in: until - now,
+                                last_error: self.status.last_error.clone().unwrap_or_default(),
+                            })),
+                        };
+                    }
+                    trace!("token fetching cooldown is over");
+                    self.state = match last {
+                        Some(last) => State::Refetching {
+                            future: RefGuard::new(self.fetch()),
+                            attempts: 1,
+                            started: now,
+                            last: last.clone(),
+                        },
+                        None => State::Fetching {
+                            future: RefGuard::new(self.fetch()),
+                            attempts: 1,
+                            started: now,
+                        },
+                    };
                 }
                 State::Fetched { ref current } => {
-                    if !current.expired(Instant::now()) {
+                    if !current.needs_refresh(Instant::now()) {
                         break Poll::Ready(Ok(()));
                     }
-                    trace!("token will expire: expiry={:?}", current.expiry);
+                    trace!("token will be refreshed: expiry={:?}", current.expiry);
                     self.state = State::Refetching {
-                        future: RefGuard::new(self.fetcher.fetch()),
+                        future: RefGuard::new(self.fetch()),
                         attempts: 1,
+                        started: Instant::now(),
                         last: current.clone(),
                     };
                 }
@@ -145,22 +398,107 @@ impl Inner {
         }
     }
 
+    /// Returns how long to wait before retrying a fetch whose `attempts`-th attempt failed with
+    /// `err`, or `None` if it is not retried. Without a timer, retries cannot wait and start
+    /// immediately instead.
+    fn retry_in(&self, attempts: u8, started: Instant, err: &auth::Error) -> Option<Duration> {
+        if attempts > self.policy.max_retry || !err.is_retryable() {
+            return None;
+        }
+        let backoff = self.policy.retry.backoff(attempts, token::random());
+        let retry_in = err
+            .retry_after()
+            .map_or(backoff, |after| after.max(backoff));
+        if started.elapsed().saturating_add(retry_in) > self.policy.retry.max_elapsed {
+            return None;
+        }
+        match self.timer {
+            Some(_) => Some(retry_in),
+            None => Some(Duration::ZERO),
+        }
+    }
+
+    /// Fetches a token, which fails with `AuthError::Timeout` once the fetch timeout has passed,
+    /// if there is a timer.
+    fn fetch(&self) -> token::ResponseFuture {
+        match self.timer {
+            Some(ref timer) => {
+                fetch_with_timeout(&*self.fetcher, &**timer, self.policy.fetch_timeout)
+            }
+            None => self.fetcher.fetch(),
+        }
+    }
+
+    /// Fetches a token once `delay` has passed.
+    fn fetch_after(&self, delay: Duration) -> token::ResponseFuture {
+        let timer = match self.timer {
+            Some(ref timer) if !delay.is_zero() => timer.clone(),
+            _ => return self.fetch(),
+        };
+        let (sleep, fetcher) = (timer.sleep(delay), self.fetcher.clone());
+        let timeout = self.policy.fetch_timeout;
+        Box::pin(async move {
+            sleep.await;
+            fetch_with_timeout(&*fetcher, &*timer, timeout).await
+        })
+    }
+
+    /// Returns the token of a subject, or the error of fetching it, after `poll_ready` did not
+    /// wait for it.
+    fn token(&self) -> auth::Result<header::HeaderValue> {
+        let now = Instant::now();
+        match self.state {
+            State::Fetched { ref current }
+            | State::Refetching {
+                last: ref current, ..
+            } if !current.expired(now) => Ok(current.value.clone()),
+            State::Failed {
+                last: Some(ref last),
+                ..
+            } if !last.expired(now) && self.policy.keep_valid_token => Ok(last.value.clone()),
+            State::Failed { until, .. } => Err(auth::Error::RefreshCooldown {
+                retry_in: until.saturating_duration_since(now),
+                last_error: self.status.last_error.clone().unwrap_or_default(),
+            }),
+            State::NotFetched | State::Fetching { .. } => Err(auth::Error::TokenFetching),
+            _ => Err(auth::Error::TokenExpired),
+        }
+    }
+
     #[inline]
     fn value(&self) -> header::HeaderValue {
         match self.state {
             State::Fetched { ref current } => current.value.clone(),
             State::Refetching { ref last, .. } => last.value.clone(),
+            State::Failed {
+                last: Some(ref last),
+                ..
+            } => last.value.clone(),
             _ => unreachable!("invalid state: {:?}", self.state),
         }
     }
 }
 
+fn fetch_with_timeout(
+    fetcher: &dyn token::Fetcher,
+    timer: &dyn Timer,
+    timeout: Duration,
+) -> token::ResponseFuture {
+    let (fetch, sleep) = (fetcher.fetch(), timer.sleep(timeout));
+    Box::pin(async move {
+        match select(fetch, sleep).await {
+            Either::Left((resp, _)) => resp,
+            Either::Right(_) => Err(auth::Error::Timeout(timeout)),
+        }
+    })
+}
+
 impl fmt::Debug for Inner {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Inner")
             .field("state", &self.state)
             .field("fetcher", &self.fetcher)
-            .field("max_retry", &self.max_retry)
+            .field("policy", &self.policy)
             .finish()
     }
 }
@@ -170,15 +508,22 @@ enum State {
     Fetching {
         future: RefGuard<token::ResponseFuture>,
         attempts: u8,
+        started: Instant,
     },
     Refetching {
         future: RefGuard<token::ResponseFuture>,
         attempts: u8,
+        started: Instant,
         last: token::Token,
     },
     Fetched {
         current: token::Token,
     },
+    /// The last fetch failed after all retries, and no fetch starts until `until`.
+    Failed {
+        last: Option<token::Token>,
+        until: Instant,
+    },
 }
 
 impl fmt::Debug for State {
@@ -188,6 +533,523 @@ impl fmt::Debug for State {
             Self::Fetching { .. } => write!(f, "Fetching"),
             Self::Refetching { .. } => write!(f, "Refetching"),
             Self::Fetched { .. } => write!(f, "Fetched"),
+            Self::Failed { until, .. } => write!(f, "Failed({:?})", until),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures_util::future::poll_fn;
+    use parking_lot::Mutex;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Fetcher {
+        subject: Option<String>,
+    }
+
+    impl token::Fetcher for Fetcher {
+        fn fetch(&self) -> token::ResponseFuture {
+            let access_token = self.subject.clone().unwrap_or_else(|| "default".into());
+            match access_token.as_str() {
+                "revoked@example.com" => return Box::pin(async { Err(auth::Error::TokenExpired) }),
+                "slow@example.com" => return Box::pin(futures_util::future::pending()),
+                _ => {}
+            }
+            Box::pin(async move {
+                Ok(token::Response::AccessToken {
+                    token_type: "Bearer".into(),
+                    access_token,
+                    expires_in: 3600,
+                })
+            })
+        }
+
+        fn with_subject(&self, subject: &str) -> Option<Box<dyn token::Fetcher>> {
+            Some(Box::new(Fetcher {
+                subject: Some(subject.into()),
+            }))
+        }
+    }
+
+    // Retries without a backoff, and needs no runtime as there is no timer.
+    fn config(max_retry: u8) -> auth::Config {
+        auth::Config {
+            max_retry,
+            retry: token::RetryPolicy {
+                initial_backoff: Duration::ZERO,
+                ..Default::default()
+            },
+            timer: None,
+            ..Default::default()
+        }
+    }
+
+    fn inner(fetcher: impl token::Fetcher, config: auth::Config) -> Inner {
+        Inner::new(Box::new(fetcher), (&config).into(), config.timer.clone())
+    }
+
+    fn request(subject: Option<&str>) -> Request<()> {
+        let mut req = Request::new(());
+        if let Some(subject) = subject {
+            req.extensions_mut().insert(Subject(subject.into()));
+        }
+        req
+    }
+
+    fn authorization(oauth2: &Oauth2, subject: Option<&str>) -> auth::Result<header::HeaderValue> {
+        let req = oauth2.call(request(subject))?;
+        Ok(req.headers()[AUTHORIZATION].clone())
+    }
+
+    #[tokio::test]
+    async fn test_subject() {
+        let config = auth::Config {
+            subjects: vec!["user@example.com".into(), "revoked@example.com".into()],
+            ..Default::default()
+        };
+        let mut oauth2 = Oauth2::new(Box::new(Fetcher { subject: None }), &config);
+        poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        assert_eq!(authorization(&oauth2, None).unwrap(), "Bearer default");
+        assert_eq!(
+            authorization(&oauth2, Some("user@example.com")).unwrap(),
+            "Bearer user@example.com"
+        );
+        // The failure of a subject only fails the requests acting as it.
+        assert!(matches!(
+            authorization(&oauth2, Some("revoked@example.com")),
+            Err(auth::Error::RefreshCooldown { .. })
+        ));
+        assert!(matches!(
+            authorization(&oauth2, Some("other@example.com")),
+            Err(auth::Error::UnsupportedSubject(subject)) if subject == "other@example.com"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_slow_subject() {
+        let config = auth::Config {
+            subjects: vec!["slow@example.com".into()],
+            ..config(0)
+        };
+        let mut oauth2 = Oauth2::new(Box::new(Fetcher { subject: None }), &config);
+        // The fetch of the subject never ends, which does not hold up the default token.
+        poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        assert_eq!(authorization(&oauth2, None).unwrap(), "Bearer default");
+        assert!(matches!(
+            authorization(&oauth2, Some("slow@example.com")),
+            Err(auth::Error::TokenFetching)
+        ));
+        poll_fn(|cx| oauth2.poll_ready(cx)).await.unwrap();
+        assert_eq!(authorization(&oauth2, None).unwrap(), "Bearer default");
+    }
+
+    #[derive(Debug)]
+    struct Once(std::sync::atomic::AtomicBool);
+
+    impl token::Fetcher for Once {
+        // Only the first token arrives.
+        fn fetch(&self) -> token::ResponseFuture {
+            if self.0.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                return Box::pin(futures_util::future::pending());
+            }
+            Box::pin(async {
+                Ok(token::Response::AccessToken {
+                    token_type: "Bearer".into(),
+                    access_token: "first".into(),
+                    expires_in: 3600,
+                })
+            })
+        }
+    }
+
+    #[test]
+    fn test_refresh_ahead() {
+        let waker = futures_util::task::noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        let mut inner = inner(Once(Default::default()), config(0));
+        assert!(inner.poll_ready(&mut cx).is_ready());
+        match inner.state {
+            State::Fetched { ref current } => {
+                assert_eq!(
+                    current.expiry - current.refresh_at,
+                    Duration::from_secs(225)
+                )
+            }
+            _ => unreachable!(),
+        }
+        assert!(inner.can_skip_poll_ready());
+
+        // The current token is used while the refresh is in flight.
+        if let State::Fetched { ref mut current } = inner.state {
+            current.refresh_at = Instant::now();
+        }
+        assert!(!inner.can_skip_poll_ready());
+        assert!(matches!(inner.poll_ready(&mut cx), Poll::Ready(Ok(()))));
+        assert!(matches!(inner.state, State::Refetching { .. }));
+        assert_eq!(inner.value(), "Bearer first");
+        // Callers that did not wait are not woken by the refresh.
+        assert_eq!(inner.wakers.len(), 0);
+
+        // Requests wait once the current token can no longer be used.
+        if let State::Refetching { ref mut last, .. } = inner.state {
+            last.expiry = Instant::now();
+        }
+        assert!(inner.poll_ready(&mut cx).is_pending());
+        assert_eq!(inner.wakers.len(), 1);
+    }
+
+    // A fetch that completes once opened.
+    #[derive(Debug, Default)]
+    struct Gate(Arc<Mutex<(bool, Option<Waker>)>>);
+
+    impl Gate {
+        fn open(&self) {
+            let mut gate = self.0.lock();
+            gate.0 = true;
+            if let Some(waker) = gate.1.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    impl token::Fetcher for Gate {
+        fn fetch(&self) -> token::ResponseFuture {
+            let gate = self.0.clone();
+            Box::pin(poll_fn(move |cx| {
+                let mut gate = gate.lock();
+                if !gate.0 {
+                    gate.1 = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                Poll::Ready(Ok(token::Response::AccessToken {
+                    token_type: "Bearer".into(),
+                    access_token: "token".into(),
+                    expires_in: 3600,
+                }))
+            }))
+        }
+    }
+
+    #[derive(Default)]
+    struct Counter(std::sync::atomic::AtomicUsize);
+
+    impl futures_util::task::ArcWake for Counter {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_wake_all() {
+        let gate = Gate::default();
+        let mut first = Oauth2::new(Box::new(Gate(gate.0.clone())), &config(3));
+        let mut second = first.clone();
+
+        let counters = [Arc::new(Counter::default()), Arc::new(Counter::default())];
+        let wakers = counters
+            .iter()
+            .map(|counter| futures_util::task::waker(counter.clone()))
+            .collect::<Vec<_>>();
+        assert!(first
+            .poll_ready(&mut task::Context::from_waker(&wakers[0]))
+            .is_pending());
+        assert!(second
+            .poll_ready(&mut task::Context::from_waker(&wakers[1]))
+            .is_pending());
+
+        // Both clones are woken, although the fetch was last polled by the second one.
+        gate.open();
+        for counter in &counters {
+            assert_eq!(counter.0.load(std::sync::atomic::Ordering::SeqCst), 1);
+        }
+        assert!(first
+            .poll_ready(&mut task::Context::from_waker(&wakers[0]))
+            .is_ready());
+        assert!(second
+            .poll_ready(&mut task::Context::from_waker(&wakers[1]))
+            .is_ready());
+    }
+
+    // Fails with the status code in `fail` unless it is zero, asking 429s to retry after
+    // `retry_after`, or a minute if it is empty.
+    #[derive(Debug, Default)]
+    struct Flaky {
+        fail: Arc<std::sync::atomic::AtomicU16>,
+        fetches: Arc<std::sync::atomic::AtomicUsize>,
+        retry_after: &'static str,
+    }
+
+    impl token::Fetcher for Flaky {
+        fn fetch(&self) -> token::ResponseFuture {
+            use std::sync::atomic::Ordering;
+            let n = self.fetches.fetch_add(1, Ordering::SeqCst);
+            let fail = self.fail.load(Ordering::SeqCst);
+            let retry_after = match self.retry_after {
+                "" => "60",
+                retry_after => retry_after,
+            };
+            Box::pin(async move {
+                if fail != 0 {
+                    let mut resp = hyper::Response::builder().status(fail);
+                    if fail == 429 {
+                        resp = resp.header(header::RETRY_AFTER, retry_after);
+                    }
+                    let resp = resp.body(hyper::Body::empty()).unwrap();
+                    return Err(auth::Error::StatusCode(Box::new(resp.into_parts())));
+                }
+                Ok(token::Response::AccessToken {
+                    token_type: "Bearer".into(),
+                    access_token: format!("token-{}", n),
+                    expires_in: 3600,
+                })
+            })
+        }
+    }
+
+    // Ends the cooldown after a failed fetch without waiting for it.
+    fn end_cooldown(inner: &mut Inner) {
+        if let State::Failed { ref mut until, .. } = inner.state {
+            *until = Instant::now();
+        }
+    }
+
+    #[test]
+    fn test_recovery() {
+        use std::sync::atomic::Ordering;
+
+        let waker = futures_util::task::noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        let flaky = Flaky::default();
+        let (fail, fetches) = (flaky.fail.clone(), flaky.fetches.clone());
+        let mut inner = inner(
+            flaky,
+            auth::Config {
+                retry_cooldown: Duration::from_secs(60),
+                keep_valid_token: false,
+                ..config(1)
+            },
+        );
+
+        // Each round fetches once and retries once.
+        fail.store(503, Ordering::SeqCst);
+        for round in 1..=3 {
+            assert!(matches!(
+                inner.poll_ready(&mut cx),
+                Poll::Ready(Err(auth::Error::StatusCode(_)))
+            ));
+            assert_eq!(fetches.load(Ordering::SeqCst), round * 2);
+            assert_eq!(inner.status.consecutive_failures, round as u32);
+
+            // No token is fetched during the cooldown.
+            assert!(matches!(
+                inner.poll_ready(&mut cx),
+                Poll::Ready(Err(auth::Error::RefreshCooldown { last_error, .. }))
+                    if last_error.contains("503")
+            ));
+            assert_eq!(fetches.load(Ordering::SeqCst), round * 2);
+            end_cooldown(&mut inner);
+        }
+
+        fail.store(0, Ordering::SeqCst);
+        assert!(matches!(inner.poll_ready(&mut cx), Poll::Ready(Ok(()))));
+        assert_eq!(inner.value(), "Bearer token-6");
+        assert_eq!(inner.status.consecutive_failures, 0);
+        assert!(inner.status.last_error.is_none());
+    }
+
+    #[test]
+    fn test_keep_valid_token() {
+        use std::sync::atomic::Ordering;
+
+        let waker = futures_util::task::noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        let flaky = Flaky::default();
+        let (fail, fetches) = (flaky.fail.clone(), flaky.fetches.clone());
+        let mut inner = inner(
+            flaky,
+            auth::Config {
+                retry_cooldown: Duration::from_secs(60),
+                ..config(1)
+            },
+        );
+        assert!(matches!(inner.poll_ready(&mut cx), Poll::Ready(Ok(()))));
+
+        // The current token is used after a failed refresh, and during the cooldown.
+        fail.store(503, Ordering::SeqCst);
+        if let State::Fetched { ref mut current } = inner.state {
+            current.refresh_at = Instant::now();
+        }
+        assert!(matches!(inner.poll_ready(&mut cx), Poll::Ready(Ok(()))));
+        assert!(matches!(inner.poll_ready(&mut cx), Poll::Ready(Ok(()))));
+        assert_eq!(inner.value(), "Bearer token-0");
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        assert_eq!(inner.status.consecutive_failures, 1);
+
+        // The refresh is retried after the cooldown.
+        end_cooldown(&mut inner);
+        assert!(matches!(inner.poll_ready(&mut cx), Poll::Ready(Ok(()))));
+        assert_eq!(fetches.load(Ordering::SeqCst), 5);
+
+        // Requests fail once the current token can no longer be used.
+        if let State::Failed {
+            last: Some(ref mut last),
+            ..
+        } = inner.state
+        {
+            last.expiry = Instant::now();
+        }
+        assert!(matches!(
+            inner.poll_ready(&mut cx),
+            Poll::Ready(Err(auth::Error::RefreshCooldown { .. }))
+        ));
+
+        end_cooldown(&mut inner);
+        fail.store(0, Ordering::SeqCst);
+        assert!(matches!(inner.poll_ready(&mut cx), Poll::Ready(Ok(()))));
+        assert_eq!(inner.value(), "Bearer token-5");
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        use std::sync::atomic::Ordering;
+
+        let waker = futures_util::task::noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        let flaky = Flaky::default();
+        let (fail, fetches) = (flaky.fail.clone(), flaky.fetches.clone());
+        let mut inner = inner(flaky, config(3));
+
+        // Permanent errors are not retried.
+        fail.store(400, Ordering::SeqCst);
+        assert!(matches!(
+            inner.poll_ready(&mut cx),
+            Poll::Ready(Err(auth::Error::StatusCode(_)))
+        ));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // A `Retry-After` beyond the max elapsed time ends the retries, and is waited instead of
+        // the shorter cooldown.
+        inner.state = State::NotFetched;
+        fail.store(429, Ordering::SeqCst);
+        assert!(matches!(
+            inner.poll_ready(&mut cx),
+            Poll::Ready(Err(auth::Error::StatusCode(_)))
+        ));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+        assert!(inner.refresh_in(Instant::now()).unwrap() > Duration::from_secs(59));
+
+        // Retries wait for the backoff.
+        struct Never;
+        impl Timer for Never {
+            fn sleep(&self, _: Duration) -> futures_util::future::BoxFuture<'static, ()> {
+                Box::pin(futures_util::future::pending())
+            }
+        }
+        let mut inner = self::inner(
+            Flaky {
+                fail: Arc::new(503.into()),
+                ..Default::default()
+            },
+            auth::Config {
+                retry: token::RetryPolicy {
+                    initial_backoff: Duration::from_secs(60),
+                    ..Default::default()
+                },
+                timer: Some(Arc::new(Never)),
+                ..config(3)
+            },
+        );
+        assert!(inner.poll_ready(&mut cx).is_pending());
+        assert!(matches!(inner.state, State::Fetching { attempts: 2, .. }));
+
+        // Without a timer, retries start immediately instead of waiting for the backoff.
+        let flaky = Flaky {
+            fail: Arc::new(503.into()),
+            ..Default::default()
+        };
+        let fetches = flaky.fetches.clone();
+        let mut inner = self::inner(
+            flaky,
+            auth::Config {
+                retry: Default::default(),
+                ..config(3)
+            },
+        );
+        assert!(matches!(
+            inner.poll_ready(&mut cx),
+            Poll::Ready(Err(auth::Error::StatusCode(_)))
+        ));
+        assert_eq!(fetches.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_retry_after_overflow() {
+        let waker = futures_util::task::noop_waker();
+        let mut cx = task::Context::from_waker(&waker);
+        let flaky = || Flaky {
+            fail: Arc::new(429.into()),
+            retry_after: "18446744073709551615",
+            ..Default::default()
+        };
+
+        // The delay is capped, and ends the retries as it is beyond the max elapsed time.
+        let mut inner = inner(flaky(), config(3));
+        assert!(matches!(
+            inner.poll_ready(&mut cx),
+            Poll::Ready(Err(auth::Error::StatusCode(_)))
+        ));
+        let cooldown = inner.refresh_in(Instant::now()).unwrap();
+        assert!(
+            cooldown > Duration::from_secs(59 * 60) && cooldown <= Duration::from_secs(60 * 60)
+        );
+
+        // Neither does a cooldown too long to be added to an `Instant` panic.
+        let mut inner = self::inner(
+            flaky(),
+            auth::Config {
+                retry_cooldown: Duration::MAX,
+                ..config(3)
+            },
+        );
+        assert!(matches!(
+            inner.poll_ready(&mut cx),
+            Poll::Ready(Err(auth::Error::StatusCode(_)))
+        ));
+        assert!(inner.refresh_in(Instant::now()).unwrap() > Duration::from_secs(60 * 60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_fetch_timeout() {
+        // The gate is never opened.
+        let mut inner = self::inner(
+            Gate::default(),
+            auth::Config {
+                fetch_timeout: Duration::from_millis(50),
+                timer: Some(Arc::new(crate::testing::Sleep)),
+                ..config(0)
+            },
+        );
+        assert!(matches!(
+            poll_fn(|cx| inner.poll_ready(cx)).await,
+            Err(auth::Error::Timeout(timeout)) if timeout == Duration::from_millis(50)
+        ));
+
+        // Without a timer, the fetch does not time out.
+        let mut inner = self::inner(
+            Gate::default(),
+            auth::Config {
+                fetch_timeout: Duration::ZERO,
+                ..config(0)
+            },
+        );
+        let waker = futures_util::task::noop_waker();
+        assert!(inner
+            .poll_ready(&mut task::Context::from_waker(&waker))
+            .is_pending());
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures_util::future::{poll_fn, AbortHandle, Abortable};
+use parking_lot::RwLock;
+use tracing::{trace, warn};
+
+use crate::{
+    auth::oauth2::Inner,
+    runtime::{Executor, Timer},
+};
+
+/// A failed refresh is retried after this long.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Keeps the token of `Inner` fresh in a background task, which is aborted when this is dropped.
+pub(super) struct Refresher(AbortHandle);
+
+impl Refresher {
+    pub fn spawn(
+        inner: Arc<RwLock<Inner>>,
+        executor: &dyn Executor,
+        timer: Arc<dyn Timer>,
+    ) -> Self {
+        let (handle, registration) = AbortHandle::new_pair();
+        let task = Abortable::new(run(inner, timer), registration);
+        executor.spawn(Box::pin(async move {
+            let _ = task.await;
+            trace!("background token refresher stopped");
+        }));
+        Self(handle)
+    }
+}
+
+impl Drop for Refresher {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+async fn run(inner: Arc<RwLock<Inner>>, timer: Arc<dyn Timer>) {
+    loop {
+        let result = poll_fn(|cx| {
+            let mut inner = inner.write();
+            let wakers = inner.wakers.clone();
+            wakers.poll(cx.waker(), || inner.poll_fetch())
+        })
+        .await;
+        if let Err(err) = result {
+            warn!("background token refresh failed: {}", err);
+        }
+        // Until the next refresh, or the end of the cooldown after a failure.
+        let wait = inner
+            .read()
+            .refresh_in(Instant::now())
+            .unwrap_or(RETRY_INTERVAL);
+        trace!("next background token refresh in {:?}", wait);
+        timer.sleep(wait).await;
+    }
+}
+
+#[cfg(all(test, feature = "tokio"))]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        auth::{
+            self,
+            oauth2::{token, State},
+        },
+        runtime::Tokio,
+    };
+
+    #[derive(Debug)]
+    struct Fetcher(Arc<AtomicUsize>);
+
+    impl token::Fetcher for Fetcher {
+        fn fetch(&self) -> token::ResponseFuture {
+            let n = self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move {
+                Ok(token::Response::AccessToken {
+                    token_type: "Bearer".into(),
+                    access_token: format!("token-{}", n),
+                    expires_in: 60,
+                })
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_refresher() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let config = auth::Config {
+            background_refresh: Some((Arc::new(Tokio), Arc::new(Tokio))),
+            timer: Some(Arc::new(Tokio)),
+            ..Default::default()
+        };
+        let oauth2 = auth::oauth2::Oauth2::new(Box::new(Fetcher(fetches.clone())), &config);
+        let (clone, inner) = (oauth2.clone(), oauth2.inner.clone());
+        // Tokens are refreshed by the real clock, so the refresh is made due before the paused
+        // clock passes the sleep of the refresher.
+        let refresh_now = || {
+            if let State::Fetched { ref mut current } = inner.write().state {
+                current.refresh_at = Instant::now();
+            }
+        };
+
+        tokio::task::yield_now().await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        refresh_now();
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+        let status = oauth2.status();
+        assert!(status.last_refresh.is_some());
+        assert_eq!(status.consecutive_failures, 0);
+
+        // The task stops with the last clone.
+        drop(oauth2);
+        refresh_now();
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        drop(clone);
+        refresh_now();
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,4 +1,7 @@
-use std::{fmt, time::SystemTime};
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
 
 use hyper::Uri;
 use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
@@ -11,12 +14,12 @@ use crate::{
 
 // If client machine's time is in the future according
 // to Google servers, an access token will not be issued.
-fn issued_at() -> u64 {
+pub(super) fn issued_at() -> u64 {
     SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs() - 10
 }
 
 // https://cloud.google.com/iot/docs/concepts/device-security#security_standards
-fn header(typ: impl Into<String>, key_id: impl Into<String>) -> Header {
+pub(super) fn header(typ: impl Into<String>, key_id: impl Into<String>) -> Header {
     Header {
         typ: Some(typ.into()),
         alg: Algorithm::RS256,
@@ -46,6 +49,7 @@ struct Payload<'a> {
 }
 
 // https://cloud.google.com/docs/authentication/production
+#[derive(Clone)]
 pub struct ServiceAccount {
     inner: Client,
     header: Header,
@@ -55,19 +59,22 @@ pub struct ServiceAccount {
     scopes: String,
     client_email: String,
     audience: Option<String>,
+    subject: Option<String>,
 }
 
 impl ServiceAccount {
-    pub(crate) fn new(sa: credentials::ServiceAccount) -> Self {
+    pub(crate) fn new(sa: credentials::ServiceAccount, connect_timeout: Duration) -> Self {
         Self {
-            inner: Client::new(),
+            inner: Client::new(connect_timeout),
             header: header("JWT", sa.private_key_id),
-            private_key: EncodingKey::from_rsa_pem(sa.private_key.as_bytes()).unwrap(),
-            token_uri: Uri::from_maybe_shared(sa.token_uri.clone()).unwrap(),
+            private_key: sa.private_key.key,
+            token_uri: Uri::from_maybe_shared(sa.token_uri.clone())
+                .expect("token uri is validated"),
             token_uri_str: sa.token_uri,
             scopes: sa.scopes.join(" "),
             client_email: sa.client_email,
-            audience: sa.audience.map(Into::into),
+            audience: sa.audience,
+            subject: sa.subject,
         }
     }
 }
@@ -94,10 +101,11 @@ impl token::Fetcher for ServiceAccount {
             iat,
             exp: iat + EXPIRE,
             target_audience: self.audience.as_deref(),
-            sub: if self.audience.is_some() {
-                Some(&self.client_email)
-            } else {
-                None
+            sub: match (&self.subject, &self.audience) {
+                // https://developers.google.com/identity/protocols/oauth2/service-account#delegatingauthority
+                (Some(subject), _) => Some(subject),
+                (None, Some(_)) => Some(&self.client_email),
+                (None, None) => None,
             },
         };
 
@@ -113,4 +121,11 @@ impl token::Fetcher for ServiceAccount {
         );
         Box::pin(self.inner.send(req))
     }
+
+    fn with_subject(&self, subject: &str) -> Option<Box<dyn token::Fetcher>> {
+        Some(Box::new(Self {
+            subject: Some(subject.to_owned()),
+            ..self.clone()
+        }))
+    }
 }
@@ -1,32 +1,133 @@
 use std::{
     convert::TryFrom,
     fmt,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 use futures_util::future::BoxFuture;
 use hyper::header::HeaderValue;
 
-use crate::auth;
+use crate::auth::{
+    self,
+    oauth2::id_token::{decode_claims, Lifetime},
+};
+
+/// Tokens are no longer used this long before they expire.
+pub(crate) const EXPIRY_DELTA: Duration = Duration::from_secs(10);
 
 #[derive(Clone)]
 pub(crate) struct Token {
     pub value: HeaderValue,
     pub expiry: Instant,
+    /// When a new token is fetched while this one is still used.
+    pub refresh_at: Instant,
 }
 
 impl Token {
     pub fn new(value: HeaderValue, expiry: Instant) -> Self {
-        Self { value, expiry }
+        Self {
+            value,
+            expiry,
+            refresh_at: expiry,
+        }
     }
 
+    /// Reports whether the token can no longer be used at `at`.
     pub fn expired(&self, at: Instant) -> bool {
-        const EXPIRY_DELTA: Duration = Duration::from_secs(10);
         self.expiry
             .checked_duration_since(at)
             .map(|dur| dur < EXPIRY_DELTA)
             .unwrap_or(true)
     }
+
+    pub fn needs_refresh(&self, at: Instant) -> bool {
+        at >= self.refresh_at
+    }
+}
+
+/// Decides when tokens are refreshed ahead of their expiry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RefreshPolicy {
+    pub window: RefreshWindow,
+    /// Tokens are refreshed up to this much earlier at random, so that clients started together
+    /// do not refresh together.
+    pub jitter: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RefreshWindow {
+    /// Refreshes this long before the expiry.
+    Margin(Duration),
+    /// Refreshes once this fraction of the lifetime has elapsed.
+    Fraction(f64),
+}
+
+impl Default for RefreshPolicy {
+    fn default() -> Self {
+        Self {
+            window: RefreshWindow::Margin(Duration::from_secs(225)),
+            jitter: Duration::ZERO,
+        }
+    }
+}
+
+impl RefreshPolicy {
+    /// Returns when a token fetched at `now` is refreshed, where `random` is in `[0, 1)`.
+    /// Tokens are used for at least half of their lifetime, so that short-lived tokens are not
+    /// refreshed over and over.
+    pub fn refresh_at(&self, now: Instant, expiry: Instant, random: f64) -> Instant {
+        let lifetime = expiry.saturating_duration_since(now);
+        let ahead = match self.window {
+            RefreshWindow::Margin(margin) => margin,
+            RefreshWindow::Fraction(fraction) => lifetime.mul_f64(1.0 - fraction.clamp(0.0, 1.0)),
+        };
+        let ahead = ahead
+            .saturating_add(self.jitter.mul_f64(random))
+            .min(lifetime / 2);
+        expiry - ahead
+    }
+}
+
+/// Decides how long to wait before retrying a failed token fetch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct RetryPolicy {
+    /// The backoff before the first retry, which doubles with each retry.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// No retry starts once this long has passed since the first attempt.
+    pub max_elapsed: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+            max_elapsed: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the backoff after `attempts` failed attempts, where `random` is in `[0, 1)`. Up to
+    /// half of the backoff is cut at random, so that clients failing together do not retry
+    /// together.
+    pub fn backoff(&self, attempts: u8, random: f64) -> Duration {
+        let exp = u32::from(attempts.saturating_sub(1)).min(16);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << exp)
+            .min(self.max_backoff);
+        backoff / 2 + (backoff / 2).mul_f64(random)
+    }
+}
+
+/// Returns a random number in `[0, 1)`.
+pub(crate) fn random() -> f64 {
+    let buf: [u8; 8] = ring::rand::generate(&ring::rand::SystemRandom::new())
+        .map(|random| random.expose())
+        .unwrap_or_default();
+    (u64::from_le_bytes(buf) >> 11) as f64 / (1u64 << 53) as f64
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -65,20 +166,16 @@ impl TryFrom<Response> for Token {
                 }
             }
             Response::IdToken { ref id_token } => {
-                if !id_token.is_empty() {
-                    let value = format!("Bearer {}", id_token);
-                    HeaderValue::from_str(&value)
-                        .map(|hv| {
-                            // TODO - to get expiry, it is reccomended to decode the recieved JWT and
-                            //  use the `exp` claim contained in the payload. See here:
-                            // https://cloud.google.com/run/docs/authenticating/service-to-service#use_the_metadata_server
-                            let expiry = Instant::now() + Duration::from_secs(60 * 60);
-                            Token::new(hv, expiry)
-                        })
-                        .map_err(|_| auth::Error::TokenFormat(response))
-                } else {
-                    Err(auth::Error::TokenFormat(response))
-                }
+                // https://cloud.google.com/run/docs/authenticating/service-to-service#use_the_metadata_server
+                let lifetime = match decode_claims::<Lifetime>(id_token) {
+                    Ok(lifetime) => lifetime,
+                    Err(_) => return Err(auth::Error::TokenFormat(response)),
+                };
+                let expiry = lifetime.expiry(SystemTime::now(), Instant::now())?;
+                let value = format!("Bearer {}", id_token);
+                HeaderValue::from_str(&value)
+                    .map(|hv| Token::new(hv, expiry))
+                    .map_err(|_| auth::Error::TokenFormat(response))
             }
         }
     }
@@ -88,4 +185,115 @@ pub(crate) type ResponseFuture = BoxFuture<'static, auth::Result<Response>>;
 
 pub(crate) trait Fetcher: fmt::Debug + Send + Sync + 'static {
     fn fetch(&self) -> ResponseFuture;
+
+    /// Returns a fetcher acting as `subject` through domain-wide delegation, if supported.
+    fn with_subject(&self, _subject: &str) -> Option<Box<dyn Fetcher>> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_refresh_at() {
+        let now = Instant::now();
+        let expiry = now + Duration::from_secs(3600);
+        let policy = |window, jitter| RefreshPolicy {
+            window,
+            jitter: Duration::from_secs(jitter),
+        };
+        let margin = |secs| RefreshWindow::Margin(Duration::from_secs(secs));
+        let secs = |at: Instant| at.duration_since(now).as_secs();
+
+        assert_eq!(
+            secs(policy(margin(600), 0).refresh_at(now, expiry, 0.5)),
+            3000
+        );
+        assert_eq!(
+            secs(policy(margin(600), 60).refresh_at(now, expiry, 0.5)),
+            2970
+        );
+        assert_eq!(
+            secs(policy(RefreshWindow::Fraction(0.75), 0).refresh_at(now, expiry, 0.0)),
+            2700
+        );
+        // At least half of the lifetime is used.
+        assert_eq!(
+            secs(policy(margin(3000), 0).refresh_at(now, expiry, 0.0)),
+            1800
+        );
+        assert_eq!(
+            secs(policy(RefreshWindow::Fraction(0.1), 0).refresh_at(now, expiry, 0.0)),
+            1800
+        );
+        assert_eq!(
+            secs(policy(RefreshWindow::Margin(Duration::MAX), 60).refresh_at(now, expiry, 0.5)),
+            1800
+        );
+
+        let random = random();
+        assert!((0.0..1.0).contains(&random));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            max_elapsed: Duration::from_secs(10),
+        };
+        let millis = |attempts, random| policy.backoff(attempts, random).as_millis();
+
+        assert_eq!(millis(1, 0.0), 50);
+        assert_eq!(millis(1, 0.5), 75);
+        assert_eq!(millis(2, 0.0), 100);
+        assert_eq!(millis(3, 0.0), 200);
+        assert_eq!(millis(4, 0.999), 799);
+        // The backoff is capped.
+        assert_eq!(millis(5, 0.0), 500);
+        assert_eq!(millis(u8::MAX, 0.0), 500);
+    }
+
+    #[test]
+    fn test_id_token() {
+        let id_token = |payload: &str| Response::IdToken {
+            id_token: format!(
+                "header.{}.signature",
+                base64::encode_config(payload, base64::URL_SAFE_NO_PAD)
+            ),
+        };
+
+        let exp = SystemTime::now() + Duration::from_secs(600);
+        let exp = exp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let token = Token::try_from(id_token(&format!(
+            r#"{{"aud":"audience","sub":"123","exp":{}}}"#,
+            exp
+        )))
+        .unwrap();
+        assert!(token.expiry <= Instant::now() + Duration::from_secs(600));
+        assert!(token.expiry > Instant::now() + Duration::from_secs(590));
+        // Only the expiry is required.
+        assert!(Token::try_from(id_token(&format!(r#"{{"exp":{}}}"#, exp))).is_ok());
+        // Tokens expired by the local clock are not used.
+        assert!(matches!(
+            Token::try_from(id_token(&format!(r#"{{"exp":{}}}"#, exp - 1200))),
+            Err(auth::Error::IdTokenExpired)
+        ));
+
+        assert!(matches!(
+            Token::try_from(id_token("{}")),
+            Err(auth::Error::TokenFormat(_))
+        ));
+        assert!(matches!(
+            Token::try_from(Response::IdToken {
+                id_token: String::new()
+            }),
+            Err(auth::Error::TokenFormat(_))
+        ));
+    }
 }
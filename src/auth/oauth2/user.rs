@@ -1,9 +1,12 @@
-use std::fmt;
+use std::{fmt, time::Duration};
 
 use hyper::Uri;
 
 use crate::{
-    auth::oauth2::{http::Client, token},
+    auth::{
+        self,
+        oauth2::{http::Client, token},
+    },
     credentials,
 };
 
@@ -15,6 +18,11 @@ struct Payload<'a> {
     refresh_token: &'a str,
 }
 
+#[derive(serde::Deserialize)]
+struct IdTokenResponse {
+    id_token: Option<String>,
+}
+
 // https://cloud.google.com/docs/authentication/end-user
 pub struct User {
     inner: Client,
@@ -23,11 +31,22 @@ pub struct User {
 }
 
 impl User {
-    pub(crate) fn new(user: credentials::User) -> Self {
-        Self {
-            inner: Client::new(),
+    pub(crate) fn new(user: credentials::User, connect_timeout: Duration) -> Self {
+        let token_uri = match user.token_uri {
+            Some(ref uri) => Uri::from_maybe_shared(uri.clone()).expect("token uri is validated"),
             // https://github.com/golang/oauth2/blob/0f29369cfe4552d0e4bcddc57cc75f4d7e672a33/google/google.go#L24
-            token_uri: Uri::from_static("https://oauth2.googleapis.com/token"),
+            None => format!(
+                "https://oauth2.{}/token",
+                user.universe_domain
+                    .as_deref()
+                    .unwrap_or(credentials::DEFAULT_UNIVERSE_DOMAIN)
+            )
+            .parse()
+            .expect("universe domain is validated"),
+        };
+        Self {
+            inner: Client::new(connect_timeout),
+            token_uri,
             credentials: user,
         }
     }
@@ -52,6 +71,41 @@ impl token::Fetcher for User {
                 refresh_token: &self.credentials.refresh_token,
             },
         );
-        Box::pin(self.inner.send(req))
+        if self.credentials.audience.is_none() {
+            return Box::pin(self.inner.send(req));
+        }
+        // The refresh response contains an ID token along with the access token.
+        let fut = self.inner.send(req);
+        Box::pin(async move { id_token(fut.await?) })
+    }
+}
+
+fn id_token(resp: IdTokenResponse) -> auth::Result<token::Response> {
+    match resp.id_token {
+        Some(id_token) => Ok(token::Response::IdToken { id_token }),
+        None => Err(auth::Error::MissingIdToken),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_id_token() {
+        let resp = serde_json::from_str(
+            r#"{"access_token":"access","expires_in":3599,"token_type":"Bearer","id_token":"id"}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            id_token(resp).unwrap(),
+            token::Response::IdToken { id_token } if id_token == "id"
+        ));
+
+        let resp = serde_json::from_str(
+            r#"{"access_token":"access","expires_in":3599,"token_type":"Bearer"}"#,
+        )
+        .unwrap();
+        assert!(matches!(id_token(resp), Err(auth::Error::MissingIdToken)));
     }
 }
@@ -2,22 +2,62 @@
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("gcemeta client error: {0}")]
-    Gcemeta(#[from] gcemeta::Error),
+    Gcemeta(Box<gcemeta::Error>),
     #[error("api key format error: {0}")]
     ApiKeyFormat(hyper::http::uri::InvalidUri),
+    #[error("access token format error: {0}")]
+    AccessTokenFormat(hyper::header::InvalidHeaderValue),
     #[error(
         "not found credentials source, please set the environment variable `RUST_LOG` to `google_authz=trace` for more details"
     )]
     CredentialsSource,
     #[error("read credentials file error: {0}")]
     CredentialsFile(std::io::Error),
-    #[error(
-        "user or service account credentials format error: user={user}, service_account={service_account})"
-    )]
+    #[error("credentials type error: {0}")]
+    CredentialsType(serde_json::Error),
+    #[error("unsupported credentials type: {0}")]
+    UnsupportedCredentialsType(String),
+    #[error("`{typ}` credentials format error: {source}")]
     CredentialsFormat {
-        user: serde_json::Error,
-        service_account: serde_json::Error,
+        typ: String,
+        source: serde_json::Error,
+    },
+    #[error("`{field}` url error: {source}")]
+    Url {
+        field: &'static str,
+        source: hyper::http::uri::InvalidUri,
     },
+    #[error("`{0}` url must use https, since credentials are sent to it")]
+    InsecureUrl(&'static str),
+    #[error("credential source header `{0}` format error")]
+    CredentialSourceHeader(String),
+    #[error("unsupported external account environment id: {0}")]
+    EnvironmentId(String),
+    #[error("executable credential source error: {0}")]
+    Executable(String),
+    #[error("impersonation source credentials must be able to fetch an access token")]
+    ImpersonationSource,
+    #[error("service account impersonation url error: {0}")]
+    ImpersonationUrl(String),
+    #[error("metadata server endpoint error: {0}")]
+    MetadataEndpoint(hyper::http::uri::InvalidUri),
+    #[error("service account `{0}` is not found on the metadata server")]
+    MetadataServiceAccount(String),
+    #[error("service account `{account}` of this instance is missing access scopes: {scopes:?}")]
+    MissingScopes {
+        account: String,
+        scopes: Vec<String>,
+    },
+    #[error("user credentials can only issue id tokens for their oauth client `{client_id}`, not for `{audience}`")]
+    UserAudience { audience: String, client_id: String },
+    #[error("quota project id format error: {0}")]
+    QuotaProjectFormat(hyper::header::InvalidHeaderValue),
+}
+
+impl From<gcemeta::Error> for Error {
+    fn from(err: gcemeta::Error) -> Self {
+        Self::Gcemeta(Box::new(err))
+    }
 }
 
 /// Wrapper for the `Result` type with an [`Error`](Error).
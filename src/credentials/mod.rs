@@ -1,20 +1,38 @@
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    env, fmt,
+    future::Future,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use hyper::client::HttpConnector;
+use futures_util::future::{BoxFuture, FutureExt as _, TryFutureExt as _};
+use hyper::{client::HttpConnector, header::HeaderValue};
+
+use crate::{metadata, runtime::Timer};
 
 mod error;
 mod impls;
 
 pub use error::*;
+pub(crate) use impls::well_known_file_path;
+
+pub(crate) const CLOUD_PLATFORM: &str = "https://www.googleapis.com/auth/cloud-platform";
+pub(crate) const DEFAULT_UNIVERSE_DOMAIN: &str = "googleapis.com";
 
 #[cfg_attr(test, derive(PartialEq))]
 #[derive(Debug)]
 pub enum Credentials {
     None,
     ApiKey(String),
+    AccessToken(AccessToken),
     User(User),
     ServiceAccount(ServiceAccount),
     Metadata(Box<Metadata>),
+    ExternalAccount(ExternalAccount),
+    Impersonated(Box<Impersonated>),
 }
 
 impl Credentials {
@@ -25,6 +43,108 @@ impl Credentials {
     pub fn builder<'a>() -> Builder<'a> {
         Builder::default()
     }
+
+    /// Returns the project that is billed for requests made with these credentials.
+    pub fn quota_project_id(&self) -> Option<&str> {
+        match self {
+            Self::None | Self::ApiKey(_) => None,
+            Self::AccessToken(at) => at.quota_project_id.as_deref(),
+            Self::User(user) => user.quota_project_id.as_deref(),
+            Self::ServiceAccount(sa) => sa.quota_project_id.as_deref(),
+            Self::Metadata(meta) => meta.quota_project_id.as_deref(),
+            Self::ExternalAccount(ea) => ea.quota_project_id.as_deref(),
+            Self::Impersonated(imp) => imp.quota_project_id.as_deref(),
+        }
+    }
+
+    /// Returns the universe domain, e.g. `googleapis.com`, whose endpoints these credentials
+    /// are valid for.
+    pub fn universe_domain(&self) -> Option<&str> {
+        let universe_domain = match self {
+            Self::None | Self::ApiKey(_) | Self::AccessToken(_) => return None,
+            Self::User(user) => &user.universe_domain,
+            Self::ServiceAccount(sa) => &sa.universe_domain,
+            Self::Metadata(meta) => &meta.universe_domain,
+            Self::ExternalAccount(ea) => &ea.universe_domain,
+            Self::Impersonated(imp) => &imp.universe_domain,
+        };
+        Some(
+            universe_domain
+                .as_deref()
+                .unwrap_or(DEFAULT_UNIVERSE_DOMAIN),
+        )
+    }
+
+    fn universe_domain_mut(&mut self) -> Option<&mut Option<String>> {
+        match self {
+            Self::None | Self::ApiKey(_) | Self::AccessToken(_) => None,
+            Self::User(user) => Some(&mut user.universe_domain),
+            Self::ServiceAccount(sa) => Some(&mut sa.universe_domain),
+            Self::Metadata(meta) => Some(&mut meta.universe_domain),
+            Self::ExternalAccount(ea) => Some(&mut ea.universe_domain),
+            Self::Impersonated(imp) => Some(&mut imp.universe_domain),
+        }
+    }
+
+    fn quota_project_id_mut(&mut self) -> Option<&mut Option<String>> {
+        match self {
+            Self::None | Self::ApiKey(_) => None,
+            Self::AccessToken(at) => Some(&mut at.quota_project_id),
+            Self::User(user) => Some(&mut user.quota_project_id),
+            Self::ServiceAccount(sa) => Some(&mut sa.quota_project_id),
+            Self::Metadata(meta) => Some(&mut meta.quota_project_id),
+            Self::ExternalAccount(ea) => Some(&mut ea.quota_project_id),
+            Self::Impersonated(imp) => Some(&mut imp.quota_project_id),
+        }
+    }
+}
+
+/// An access token or ID token obtained elsewhere, such as from `gcloud auth print-access-token`.
+pub struct AccessToken {
+    pub(crate) token: String,
+    pub(crate) expiry: Option<SystemTime>,
+    pub(crate) refresh: Option<Refresh>,
+    pub(crate) quota_project_id: Option<String>,
+}
+
+impl fmt::Debug for AccessToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessToken")
+            .field("expiry", &self.expiry)
+            .field("refresh", &self.refresh.is_some())
+            .field("quota_project_id", &self.quota_project_id)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+impl PartialEq for AccessToken {
+    fn eq(&self, other: &Self) -> bool {
+        self.token == other.token
+            && self.expiry == other.expiry
+            && self.refresh.is_some() == other.refresh.is_some()
+            && self.quota_project_id == other.quota_project_id
+    }
+}
+
+/// The result of refreshing an access token: the new token and its optional expiry.
+pub(crate) type RefreshResult =
+    std::result::Result<(String, Option<SystemTime>), Box<dyn std::error::Error + Send + Sync>>;
+
+/// A callback that obtains a new access token when the current one expires.
+#[derive(Clone)]
+pub(crate) struct Refresh(Arc<dyn Fn() -> BoxFuture<'static, RefreshResult> + Send + Sync>);
+
+impl Refresh {
+    pub(crate) fn new(
+        f: impl Fn() -> BoxFuture<'static, RefreshResult> + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub(crate) fn call(&self) -> BoxFuture<'static, RefreshResult> {
+        (self.0)()
+    }
 }
 
 #[cfg_attr(test, derive(PartialEq, Eq))]
@@ -32,10 +152,15 @@ impl Credentials {
 pub struct User {
     #[serde(skip)]
     pub(crate) scopes: Vec<String>,
+    #[serde(skip)]
+    pub(crate) audience: Option<String>,
     // json fields
     pub(crate) client_id: String,
     pub(crate) client_secret: String,
     pub(crate) refresh_token: String,
+    pub(crate) token_uri: Option<String>,
+    pub(crate) quota_project_id: Option<String>,
+    pub(crate) universe_domain: Option<String>,
 }
 
 #[cfg_attr(test, derive(PartialEq, Eq))]
@@ -45,40 +170,222 @@ pub struct ServiceAccount {
     pub(crate) scopes: Vec<String>,
     #[serde(skip)]
     pub(crate) audience: Option<String>,
+    #[serde(skip)]
+    pub(crate) self_signed_jwt: bool,
+    #[serde(skip)]
+    pub(crate) subject: Option<String>,
     // json fields
     pub(crate) client_email: String,
     pub(crate) private_key_id: String,
-    pub(crate) private_key: String,
+    pub(crate) private_key: PrivateKey,
     pub(crate) token_uri: String,
+    pub(crate) quota_project_id: Option<String>,
+    pub(crate) universe_domain: Option<String>,
+}
+
+/// The RSA private key of a service account, parsed once when the credentials are loaded.
+#[derive(Clone, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct PrivateKey {
+    pub(crate) key: jsonwebtoken::EncodingKey,
+    #[cfg(test)]
+    pem: String,
+}
+
+impl TryFrom<String> for PrivateKey {
+    type Error = String;
+
+    fn try_from(pem: String) -> std::result::Result<Self, Self::Error> {
+        let key = jsonwebtoken::EncodingKey::from_rsa_pem(pem.as_bytes())
+            .map_err(|err| format!("invalid `private_key`: {}", err))?;
+        Ok(Self {
+            key,
+            #[cfg(test)]
+            pem,
+        })
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PrivateKey")
+    }
+}
+
+#[cfg(test)]
+impl PartialEq for PrivateKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.pem == other.pem
+    }
+}
+
+#[cfg(test)]
+impl Eq for PrivateKey {}
+
+// https://google.aip.dev/auth/4117
+#[cfg_attr(test, derive(PartialEq, Eq))]
+#[derive(Debug, serde::Deserialize)]
+pub struct ExternalAccount {
+    #[serde(skip)]
+    pub(crate) scopes: Vec<String>,
+    // json fields
+    pub(crate) audience: String,
+    pub(crate) subject_token_type: String,
+    pub(crate) token_url: String,
+    pub(crate) credential_source: CredentialSource,
+    pub(crate) service_account_impersonation_url: Option<String>,
+    pub(crate) quota_project_id: Option<String>,
+    pub(crate) universe_domain: Option<String>,
+}
+
+#[cfg_attr(test, derive(PartialEq, Eq))]
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum CredentialSource {
+    // https://cloud.google.com/iam/docs/workload-identity-federation-with-other-clouds
+    Aws {
+        environment_id: String,
+        region_url: Option<String>,
+        url: Option<String>,
+        regional_cred_verification_url: String,
+        imdsv2_session_token_url: Option<String>,
+    },
+    // https://cloud.google.com/iam/docs/workload-identity-federation-with-other-providers#executable-sourced-credentials
+    Executable {
+        executable: Executable,
+    },
+    File {
+        file: String,
+        #[serde(default)]
+        format: Format,
+    },
+    Url {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        #[serde(default)]
+        format: Format,
+    },
+}
+
+#[cfg_attr(test, derive(PartialEq, Eq))]
+#[derive(Debug, serde::Deserialize)]
+pub struct Executable {
+    pub(crate) command: String,
+    #[serde(default = "Executable::default_timeout_millis")]
+    pub(crate) timeout_millis: u64,
+    pub(crate) output_file: Option<String>,
+}
+
+impl Executable {
+    fn default_timeout_millis() -> u64 {
+        30 * 1000
+    }
+}
+
+#[cfg_attr(test, derive(PartialEq, Eq))]
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Text,
+    Json {
+        subject_token_field_name: String,
+    },
+}
+
+// https://cloud.google.com/iam/docs/service-account-impersonation
+#[cfg_attr(test, derive(PartialEq))]
+#[derive(Debug)]
+pub struct Impersonated {
+    pub(crate) source: Credentials,
+    pub(crate) service_account_impersonation_url: String,
+    pub(crate) delegates: Vec<String>,
+    pub(crate) scopes: Vec<String>,
+    pub(crate) audience: Option<String>,
+    pub(crate) lifetime: Duration,
+    pub(crate) quota_project_id: Option<String>,
+    pub(crate) universe_domain: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct Metadata {
-    pub(crate) client: gcemeta::Client<HttpConnector>,
+    pub(crate) client: metadata::Client,
     pub(crate) scopes: Vec<String>,
     pub(crate) audience: Option<String>,
     pub(crate) account: Option<String>,
+    pub(crate) identity_token_format: IdentityTokenFormat,
+    pub(crate) identity_token_licenses: bool,
+    pub(crate) quota_project_id: Option<String>,
+    pub(crate) universe_domain: Option<String>,
 }
 
 #[cfg(test)]
 impl PartialEq for Metadata {
     fn eq(&self, other: &Self) -> bool {
-        self.scopes == other.scopes && self.account == other.account
+        self.scopes == other.scopes
+            && self.account == other.account
+            && self.identity_token_format == other.identity_token_format
+            && self.identity_token_licenses == other.identity_token_licenses
+            && self.quota_project_id == other.quota_project_id
+            && self.universe_domain == other.universe_domain
     }
 }
 
+#[derive(Default)]
 enum Source<'a> {
     None,
+    #[default]
     Default,
-    ApiKey { key: String },
-    Json { data: &'a [u8] },
-    JsonFile { path: &'a Path },
-    Metadata { account: Option<String> },
+    ApiKey {
+        key: String,
+    },
+    AccessToken {
+        token: String,
+        expiry: Option<SystemTime>,
+    },
+    Json {
+        data: &'a [u8],
+    },
+    JsonFile {
+        path: &'a Path,
+    },
+    Metadata {
+        account: Option<String>,
+    },
 }
 
-impl<'a> Default for Source<'a> {
-    fn default() -> Self {
-        Self::Default
+/// How the requested scopes are checked against the access scopes of a GCE instance, which
+/// limit the tokens served by the metadata server regardless of the requested scopes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScopeCheck {
+    /// Skips the check.
+    Ignore,
+    /// Logs a warning naming the missing scopes, or why they could not be looked up.
+    #[default]
+    Warn,
+    /// Fails with [`Error::MissingScopes`], or the error of looking them up.
+    Fail,
+}
+
+/// The format of identity tokens issued by the metadata server.
+///
+/// https://cloud.google.com/compute/docs/instances/verifying-instance-identity#token_format
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IdentityTokenFormat {
+    #[default]
+    Standard,
+    /// Also contains the details of the instance, see
+    /// [`InstanceIdentity`](crate::InstanceIdentity).
+    Full,
+}
+
+impl IdentityTokenFormat {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Standard => "standard",
+            Self::Full => "full",
+        }
     }
 }
 
@@ -86,14 +393,40 @@ pub struct Builder<'a> {
     scopes: Vec<String>,
     audience: Option<String>,
     source: Source<'a>,
+    impersonate: Option<(String, Vec<String>)>,
+    lifetime: Duration,
+    self_signed_jwt: bool,
+    subject: Option<String>,
+    quota_project_id: Option<String>,
+    universe_domain: Option<String>,
+    refresh: Option<Refresh>,
+    metadata_endpoint: Option<String>,
+    metadata_client: Option<hyper::Client<HttpConnector>>,
+    metadata_timeouts: metadata::Timeouts,
+    scope_check: ScopeCheck,
+    identity_token_format: IdentityTokenFormat,
+    identity_token_licenses: bool,
 }
 
 impl<'a> Default for Builder<'a> {
     fn default() -> Self {
         Self {
-            scopes: vec!["https://www.googleapis.com/auth/cloud-platform".to_owned()],
+            scopes: vec![CLOUD_PLATFORM.to_owned()],
             source: Default::default(),
             audience: Default::default(),
+            impersonate: Default::default(),
+            lifetime: Duration::from_secs(60 * 60),
+            self_signed_jwt: false,
+            subject: None,
+            quota_project_id: None,
+            universe_domain: None,
+            refresh: None,
+            metadata_endpoint: None,
+            metadata_client: None,
+            metadata_timeouts: Default::default(),
+            scope_check: ScopeCheck::default(),
+            identity_token_format: IdentityTokenFormat::default(),
+            identity_token_licenses: false,
         }
     }
 }
@@ -115,6 +448,41 @@ impl<'a> Builder<'a> {
         self
     }
 
+    /// Uses an access token or ID token as is. Without an [`expiry`](SystemTime) the token is
+    /// assumed to never expire.
+    #[must_use]
+    pub fn access_token(
+        mut self,
+        token: impl Into<String>,
+        expiry: impl Into<Option<SystemTime>>,
+    ) -> Self {
+        self.source = Source::AccessToken {
+            token: token.into(),
+            expiry: expiry.into(),
+        };
+        self
+    }
+
+    /// Sets the callback that obtains a new token whenever the current one is refreshed, which
+    /// happens ahead of its expiry, starting with the one given to
+    /// [`access_token`](Self::access_token). Without it, requests fail with
+    /// `AuthError::TokenExpired` once the token has expired.
+    #[must_use]
+    pub fn refresh_access_token<F, Fut, T, E>(mut self, f: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::result::Result<(T, Option<SystemTime>), E>> + Send + 'static,
+        T: Into<String> + 'static,
+        E: Into<Box<dyn std::error::Error + Send + Sync>> + 'static,
+    {
+        self.refresh = Some(Refresh::new(move || {
+            f().map_ok(|(token, expiry)| (token.into(), expiry))
+                .map_err(Into::into)
+                .boxed()
+        }));
+        self
+    }
+
     #[must_use]
     pub fn json<'b: 'a>(mut self, data: &'b [u8]) -> Self {
         self.source = Source::Json { data };
@@ -135,6 +503,48 @@ impl<'a> Builder<'a> {
         self
     }
 
+    /// Sets the endpoint of the metadata server as `host[:port]` or a uri such as
+    /// `http://localhost:8080`, overriding the `GCE_METADATA_HOST` and `GCE_METADATA_IP`
+    /// environment variables.
+    #[must_use]
+    pub fn metadata_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.metadata_endpoint = Some(endpoint.into());
+        self
+    }
+
+    /// Sets the http client used to access the metadata server.
+    #[must_use]
+    pub fn metadata_client(mut self, client: hyper::Client<HttpConnector>) -> Self {
+        self.metadata_client = Some(client);
+        self
+    }
+
+    /// Sets how long connecting to the metadata server may take, and how long it is looked for
+    /// before the process is assumed to not run on GCE. The connect timeout has no effect with a
+    /// [`metadata_client`](Self::metadata_client). The defaults are 2 and 3 seconds.
+    #[must_use]
+    pub fn metadata_timeout(mut self, connect: Duration, probe: Duration) -> Self {
+        self.metadata_timeouts.connect = connect;
+        self.metadata_timeouts.probe = probe;
+        self
+    }
+
+    /// Sets the timer of the [`metadata_timeout`](Self::metadata_timeout). The default is tokio
+    /// with the `tokio` feature. Without a timer, the probe only ends once the connections do.
+    #[must_use]
+    pub fn timer(mut self, timer: impl Timer) -> Self {
+        self.metadata_timeouts.timer = Some(Arc::new(timer));
+        self
+    }
+
+    /// Sets how the requested scopes are checked against the access scopes of a GCE instance
+    /// when credentials come from the metadata server. The default is [`ScopeCheck::Warn`].
+    #[must_use]
+    pub fn scope_check(mut self, scope_check: ScopeCheck) -> Self {
+        self.scope_check = scope_check;
+        self
+    }
+
     #[must_use]
     pub fn scopes(mut self, scopes: &[&str]) -> Self {
         self.scopes = scopes.iter().map(|&s| s.into()).collect();
@@ -146,18 +556,161 @@ impl<'a> Builder<'a> {
         self
     }
 
+    /// Sets the format of identity tokens issued by the metadata server for the
+    /// [`audience`](Self::audience). It has no effect for other kinds of credentials.
+    #[must_use]
+    pub fn identity_token_format(mut self, format: IdentityTokenFormat) -> Self {
+        self.identity_token_format = format;
+        self
+    }
+
+    /// Includes the license codes of the instance image in identity tokens of the
+    /// [`Full`](IdentityTokenFormat::Full) format issued by the metadata server.
+    #[must_use]
+    pub fn identity_token_licenses(mut self, enabled: bool) -> Self {
+        self.identity_token_licenses = enabled;
+        self
+    }
+
+    /// Impersonates the `target` service account, optionally through a chain of `delegates`,
+    /// using the credentials of this builder as the source.
+    #[must_use]
+    pub fn impersonate<S: Into<String>>(
+        mut self,
+        target: impl Into<String>,
+        delegates: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.impersonate = Some((
+            target.into(),
+            delegates.into_iter().map(Into::into).collect(),
+        ));
+        self
+    }
+
+    /// Sets the lifetime of impersonated access tokens. The default is one hour.
+    #[must_use]
+    pub fn impersonation_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// Authorizes service account requests with locally signed JWTs whose audience is the
+    /// origin of each request, instead of exchanging an assertion at the token endpoint.
+    /// It has no effect for ID tokens or other kinds of credentials.
+    #[must_use]
+    pub fn self_signed_jwt(mut self, enabled: bool) -> Self {
+        self.self_signed_jwt = enabled;
+        self
+    }
+
+    /// Sets the Google Workspace user that a service account acts as through domain-wide
+    /// delegation. It has no effect for other kinds of credentials.
+    #[must_use]
+    pub fn subject(mut self, email: impl Into<String>) -> Self {
+        self.subject = Some(email.into());
+        self
+    }
+
+    /// Sets the project that is billed for requests, overriding the `GOOGLE_CLOUD_QUOTA_PROJECT`
+    /// environment variable and the `quota_project_id` of credentials files.
+    #[must_use]
+    pub fn quota_project_id(mut self, project: impl Into<String>) -> Self {
+        self.quota_project_id = Some(project.into());
+        self
+    }
+
+    /// Sets the universe domain, overriding the `universe_domain` of credentials files and of
+    /// the metadata server. The default is `googleapis.com`.
+    #[must_use]
+    pub fn universe_domain(mut self, domain: impl Into<String>) -> Self {
+        self.universe_domain = Some(domain.into());
+        self
+    }
+
     pub async fn build(self) -> Result<Credentials> {
-        match self.source {
-            Source::None => Ok(Credentials::None),
-            Source::Default => impls::find_default(&self.scopes, &self.audience).await,
-            Source::ApiKey { key } => impls::from_api_key(key),
-            Source::Json { data } => impls::from_json(data, &self.scopes, &self.audience),
-            Source::JsonFile { path } => impls::from_json_file(path, &self.scopes, &self.audience),
+        // The source credentials of an impersonation only need to call the IAM Credentials API.
+        let (scopes, audience) = match self.impersonate {
+            Some(_) => (vec![CLOUD_PLATFORM.to_owned()], None),
+            None => (self.scopes.clone(), self.audience.clone()),
+        };
+        let (metadata_endpoint, metadata_client, metadata_timeouts) = (
+            self.metadata_endpoint,
+            self.metadata_client,
+            self.metadata_timeouts,
+        );
+        let metadata = || {
+            metadata::Client::new(
+                metadata_endpoint.as_deref(),
+                metadata_client.clone(),
+                metadata_timeouts.clone(),
+            )
+            .map_err(Error::MetadataEndpoint)
+        };
+        let mut credentials = match self.source {
+            Source::None => Credentials::None,
+            Source::Default => {
+                impls::find_default(metadata, self.scope_check, &scopes, &audience).await?
+            }
+            Source::ApiKey { key } => impls::from_api_key(key)?,
+            Source::AccessToken { token, expiry } => {
+                impls::from_access_token(token, expiry, self.refresh)?
+            }
+            Source::Json { data } => impls::from_json(data, &scopes, &audience)?,
+            Source::JsonFile { path } => impls::from_json_file(path, &scopes, &audience)?,
             Source::Metadata { account } => {
-                Ok(impls::from_metadata(account, &self.scopes, &self.audience)
+                impls::from_metadata(metadata()?, self.scope_check, account, &scopes, &audience)
                     .await?
-                    .expect("this process must be running on GCE"))
+                    .expect("this process must be running on GCE")
+            }
+        };
+        if let (Credentials::ServiceAccount(ref mut sa), None) =
+            (&mut credentials, &self.impersonate)
+        {
+            // Self-signed JWTs cannot act as another user.
+            sa.self_signed_jwt =
+                self.self_signed_jwt && sa.audience.is_none() && self.subject.is_none();
+            sa.subject = self.subject;
+        }
+        if let (Credentials::Metadata(ref mut meta), None) = (&mut credentials, &self.impersonate) {
+            meta.identity_token_format = self.identity_token_format;
+            meta.identity_token_licenses = self.identity_token_licenses;
+        }
+        if let (Some(universe_domain), Some(domain)) =
+            (credentials.universe_domain_mut(), self.universe_domain)
+        {
+            *universe_domain = Some(domain);
+        }
+        let universe_domain = credentials
+            .universe_domain()
+            .unwrap_or(DEFAULT_UNIVERSE_DOMAIN)
+            .to_owned();
+        // The default token endpoints are built from the universe domain.
+        impls::check_url(
+            "universe_domain",
+            &format!("https://oauth2.{}/token", universe_domain),
+        )?;
+        let mut credentials = match self.impersonate {
+            Some((target, delegates)) => impls::impersonate(
+                credentials,
+                impls::impersonation_url(&target, &universe_domain),
+                delegates,
+                self.scopes,
+                self.audience,
+                self.lifetime,
+            )?,
+            None => credentials,
+        };
+        let project = self
+            .quota_project_id
+            .or_else(|| env::var("GOOGLE_CLOUD_QUOTA_PROJECT").ok());
+        if let Some(quota_project_id) = credentials.quota_project_id_mut() {
+            if project.is_some() {
+                *quota_project_id = project;
+            }
+            if let Some(ref project) = quota_project_id {
+                HeaderValue::from_str(project).map_err(Error::QuotaProjectFormat)?;
             }
         }
+        Ok(credentials)
     }
 }
@@ -1,8 +1,19 @@
 mod auth;
 mod credentials;
+pub mod login;
+mod metadata;
+mod runtime;
 mod service;
 mod sync;
+#[cfg(test)]
+mod testing;
 
-pub use auth::Error as AuthError;
-pub use credentials::{Credentials, Error as CredentialsError};
+pub use auth::{
+    ApiKeyPlacement, Error as AuthError, IdTokenClaims, InstanceIdentity, QuotaProject,
+    RefreshStatus, Subject,
+};
+pub use credentials::{Credentials, Error as CredentialsError, IdentityTokenFormat, ScopeCheck};
+#[cfg(feature = "tokio")]
+pub use runtime::Tokio;
+pub use runtime::{Executor, Timer};
 pub use service::{Error, GoogleAuthz};
@@ -0,0 +1,765 @@
+//! Login of end users through the browser, like `gcloud auth application-default login`.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, BufRead as _, BufReader, Write as _},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use hyper::{body::to_bytes, Uri};
+use ring::{
+    digest,
+    rand::{SecureRandom as _, SystemRandom},
+};
+use tracing::{info, trace};
+
+use crate::{
+    auth::{
+        self,
+        oauth2::http::{Client, CONNECT_TIMEOUT},
+    },
+    credentials::{self, well_known_file_path, CLOUD_PLATFORM},
+    runtime::Timer,
+    sync::blocking,
+    Credentials,
+};
+
+/// Represents errors that can occur during login.
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("loopback listener error: {0}")]
+    Listener(io::Error),
+    #[error("authorization error: {0}")]
+    Authorization(String),
+    #[error("no authorization response arrived within {0:?}")]
+    AuthorizationTimeout(Duration),
+    #[error("token endpoint error: {0}")]
+    Token(#[from] auth::Error),
+    #[error("token response does not include a refresh token")]
+    RefreshToken,
+    #[error("device code expired before the user authorized it")]
+    DeviceCodeExpired,
+    #[error("a timer is needed to poll the token endpoint, enable the `tokio` feature or set one")]
+    MissingTimer,
+    #[error("write credentials file error: {0}")]
+    CredentialsFile(io::Error),
+}
+
+/// Wrapper for the `Result` type with an [`Error`](Error).
+pub type Result<T> = std::result::Result<T, Error>;
+
+const AUTH_URI: &str = "https://accounts.google.com/o/oauth2/v2/auth";
+const TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+const REVOKE_URI: &str = "https://oauth2.googleapis.com/revoke";
+const DEVICE_CODE_URI: &str = "https://oauth2.googleapis.com/device/code";
+
+// How often the loopback listener checks whether the login timed out or was dropped.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(serde::Serialize)]
+struct AuthorizationRequest<'a> {
+    client_id: &'a str,
+    redirect_uri: &'a str,
+    response_type: &'a str,
+    scope: &'a str,
+    code_challenge: &'a str,
+    code_challenge_method: &'a str,
+    state: &'a str,
+}
+
+#[derive(serde::Serialize)]
+struct TokenRequest<'a> {
+    client_id: &'a str,
+    client_secret: &'a str,
+    code: &'a str,
+    code_verifier: &'a str,
+    grant_type: &'a str,
+    redirect_uri: &'a str,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    refresh_token: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct DeviceCodeRequest<'a> {
+    client_id: &'a str,
+    scope: &'a str,
+}
+
+#[derive(serde::Deserialize)]
+struct DeviceCodeResponse {
+    device_code: String,
+    user_code: String,
+    #[serde(alias = "verification_uri")]
+    verification_url: String,
+    expires_in: u64,
+    #[serde(default = "DeviceCodeResponse::default_interval")]
+    interval: u64,
+}
+
+impl DeviceCodeResponse {
+    fn default_interval() -> u64 {
+        5
+    }
+}
+
+#[derive(serde::Serialize)]
+struct DeviceTokenRequest<'a> {
+    client_id: &'a str,
+    client_secret: &'a str,
+    device_code: &'a str,
+    grant_type: &'a str,
+}
+
+#[derive(serde::Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+#[derive(serde::Serialize)]
+struct AuthorizedUser<'a> {
+    client_id: &'a str,
+    client_secret: &'a str,
+    refresh_token: &'a str,
+    #[serde(rename = "type")]
+    typ: &'a str,
+}
+
+// https://developers.google.com/identity/protocols/oauth2/native-app
+pub struct Login {
+    client: Client,
+    token_uri: Uri,
+    client_id: String,
+    client_secret: String,
+    scopes: Vec<String>,
+    save: bool,
+    timeout: Duration,
+    open: Box<dyn Fn(&str) + Send + Sync>,
+}
+
+impl Login {
+    /// Creates a login with the OAuth client of a desktop application.
+    pub fn new(client_id: impl Into<String>, client_secret: impl Into<String>) -> Self {
+        Self {
+            client: Client::new(CONNECT_TIMEOUT),
+            token_uri: Uri::from_static(TOKEN_URI),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            scopes: vec![CLOUD_PLATFORM.to_owned()],
+            save: false,
+            timeout: Duration::from_secs(5 * 60),
+            open: Box::new(|url| info!("go to the following link in your browser: {}", url)),
+        }
+    }
+
+    #[must_use]
+    pub fn scopes(mut self, scopes: &[&str]) -> Self {
+        self.scopes = scopes.iter().map(|&s| s.into()).collect();
+        self
+    }
+
+    /// Sets the callback that shows the authorization url to the user, e.g. by opening a browser.
+    /// By default the url is only logged at the info level.
+    #[must_use]
+    pub fn on_authorization_url(mut self, f: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.open = Box::new(f);
+        self
+    }
+
+    /// Sets how long to wait for the user to authorize, after which the login fails with
+    /// [`Error::AuthorizationTimeout`]. The default is 5 minutes.
+    #[must_use]
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Writes the credentials to the well-known file of application default credentials, where
+    /// they are found by [`Credentials::new`].
+    #[must_use]
+    pub fn save_application_default(mut self, save: bool) -> Self {
+        self.save = save;
+        self
+    }
+
+    /// Asks the user for consent and exchanges the authorization code, which is received by a
+    /// one-shot listener on the loopback address, for user credentials.
+    pub async fn run(self) -> Result<Credentials> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).map_err(Error::Listener)?;
+        let redirect_uri = format!("http://{}", listener.local_addr().map_err(Error::Listener)?);
+        let verifier = random(32);
+        let state = random(16);
+        let query = serde_urlencoded::to_string(AuthorizationRequest {
+            client_id: &self.client_id,
+            redirect_uri: &redirect_uri,
+            response_type: "code",
+            scope: &self.scopes.join(" "),
+            code_challenge: &code_challenge(&verifier),
+            code_challenge_method: "S256",
+            state: &state,
+        })
+        .unwrap();
+        (self.open)(&format!("{}?{}", AUTH_URI, query));
+
+        trace!("wait for the authorization response on {}", redirect_uri);
+        // The listener stops once the login times out or its future is dropped.
+        let cancel = Cancel::default();
+        let (stopped, timeout) = (cancel.0.clone(), self.timeout);
+        let code = blocking(move || receive(listener, &state, timeout, &stopped)).await?;
+
+        let req = self.client.request(
+            &self.token_uri,
+            &TokenRequest {
+                client_id: &self.client_id,
+                client_secret: &self.client_secret,
+                code: &code,
+                code_verifier: &verifier,
+                grant_type: "authorization_code",
+                redirect_uri: &redirect_uri,
+            },
+        );
+        let resp: TokenResponse = self.client.send(req).await?;
+        let user = credentials::User {
+            scopes: self.scopes,
+            audience: None,
+            client_id: self.client_id,
+            client_secret: self.client_secret,
+            refresh_token: resp.refresh_token.ok_or(Error::RefreshToken)?,
+            token_uri: None,
+            quota_project_id: None,
+            universe_domain: None,
+        };
+        if self.save {
+            save(&user).map_err(Error::CredentialsFile)?;
+        }
+        Ok(Credentials::User(user))
+    }
+}
+
+impl fmt::Debug for Login {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Login")
+            .field("client_id", &self.client_id)
+            .field("scopes", &self.scopes)
+            .field("save", &self.save)
+            .finish()
+    }
+}
+
+/// Revokes a refresh token or an access token, e.g. on logout.
+pub async fn revoke(token: &str) -> Result<()> {
+    revoke_at(
+        &Client::new(CONNECT_TIMEOUT),
+        &Uri::from_static(REVOKE_URI),
+        token,
+    )
+    .await
+}
+
+async fn revoke_at(client: &Client, uri: &Uri, token: &str) -> Result<()> {
+    let req = client.request(uri, &[("token", token)]);
+    client.send_bytes(req).await?;
+    Ok(())
+}
+
+/// The code that the user enters at the verification url to authorize a device.
+#[derive(Clone, Debug)]
+pub struct DeviceCode {
+    pub user_code: String,
+    pub verification_url: String,
+    pub expires_in: Duration,
+}
+
+// https://developers.google.com/identity/protocols/oauth2/limited-input-device
+pub struct DeviceLogin {
+    client: Client,
+    device_code_uri: Uri,
+    token_uri: Uri,
+    client_id: String,
+    client_secret: String,
+    scopes: Vec<String>,
+    prompt: Box<dyn Fn(&DeviceCode) + Send + Sync>,
+    timer: Option<Arc<dyn Timer>>,
+}
+
+impl DeviceLogin {
+    /// Creates a login with the OAuth client of a TVs and limited input devices application.
+    pub fn new(client_id: impl Into<String>, client_secret: impl Into<String>) -> Self {
+        Self {
+            client: Client::new(CONNECT_TIMEOUT),
+            device_code_uri: Uri::from_static(DEVICE_CODE_URI),
+            token_uri: Uri::from_static(TOKEN_URI),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            scopes: vec![CLOUD_PLATFORM.to_owned()],
+            prompt: Box::new(|code| {
+                info!(
+                    "go to {} and enter the code: {}",
+                    code.verification_url, code.user_code
+                )
+            }),
+            timer: crate::runtime::default_timer(),
+        }
+    }
+
+    #[must_use]
+    pub fn scopes(mut self, scopes: &[&str]) -> Self {
+        self.scopes = scopes.iter().map(|&s| s.into()).collect();
+        self
+    }
+
+    /// Sets the callback that shows the user code and the verification url to the user.
+    /// By default they are only logged at the info level.
+    #[must_use]
+    pub fn on_device_code(mut self, f: impl Fn(&DeviceCode) + Send + Sync + 'static) -> Self {
+        self.prompt = Box::new(f);
+        self
+    }
+
+    /// Sets the timer that waits between polls of the token endpoint. The default is tokio with
+    /// the `tokio` feature, and [`run`](Self::run) fails with [`Error::MissingTimer`] otherwise.
+    #[must_use]
+    pub fn timer(mut self, timer: impl Timer) -> Self {
+        self.timer = Some(Arc::new(timer));
+        self
+    }
+
+    /// Requests a device code and polls the token endpoint until the user authorizes it.
+    pub async fn run(self) -> Result<Credentials> {
+        let timer = self.timer.clone().ok_or(Error::MissingTimer)?;
+        let req = self.client.request(
+            &self.device_code_uri,
+            &DeviceCodeRequest {
+                client_id: &self.client_id,
+                scope: &self.scopes.join(" "),
+            },
+        );
+        let code: DeviceCodeResponse = self.client.send(req).await?;
+        let expires_in = Duration::from_secs(code.expires_in);
+        (self.prompt)(&DeviceCode {
+            user_code: code.user_code,
+            verification_url: code.verification_url,
+            expires_in,
+        });
+
+        let deadline = Instant::now() + expires_in;
+        let mut interval = Duration::from_secs(code.interval);
+        loop {
+            timer.sleep(interval).await;
+            if Instant::now() >= deadline {
+                return Err(Error::DeviceCodeExpired);
+            }
+
+            let req = self.client.request(
+                &self.token_uri,
+                &DeviceTokenRequest {
+                    client_id: &self.client_id,
+                    client_secret: &self.client_secret,
+                    device_code: &code.device_code,
+                    grant_type: "urn:ietf:params:oauth:grant-type:device_code",
+                },
+            );
+            let err = match self.client.send::<TokenResponse>(req).await {
+                Ok(resp) => {
+                    return Ok(Credentials::User(credentials::User {
+                        scopes: self.scopes,
+                        audience: None,
+                        client_id: self.client_id,
+                        client_secret: self.client_secret,
+                        refresh_token: resp.refresh_token.ok_or(Error::RefreshToken)?,
+                        token_uri: None,
+                        quota_project_id: None,
+                        universe_domain: None,
+                    }))
+                }
+                // Like a pending authorization, transient failures are polled again until the
+                // device code expires.
+                Err(err) if err.is_retryable() => {
+                    trace!("failed to poll the device token, will retry: {}", err);
+                    continue;
+                }
+                Err(err) => error_code(err).await?,
+            };
+            // https://datatracker.ietf.org/doc/html/rfc8628#section-3.5
+            match err.as_str() {
+                "authorization_pending" => trace!("device authorization is pending"),
+                "slow_down" => {
+                    interval += Duration::from_secs(5);
+                    trace!("slow down polling: interval={:?}", interval);
+                }
+                "expired_token" => return Err(Error::DeviceCodeExpired),
+                _ => return Err(Error::Authorization(err)),
+            }
+        }
+    }
+}
+
+impl fmt::Debug for DeviceLogin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceLogin")
+            .field("client_id", &self.client_id)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+/// Returns the OAuth error code of an error response, or `err` itself if it has none.
+async fn error_code(err: auth::Error) -> Result<String> {
+    let (parts, body) = match err {
+        auth::Error::StatusCode(resp) => *resp,
+        err => return Err(err.into()),
+    };
+    let buf = to_bytes(body).await.map_err(auth::Error::Http)?;
+    match serde_json::from_slice::<ErrorResponse>(&buf) {
+        Ok(resp) => Ok(resp.error),
+        Err(_) => Err(auth::Error::StatusCode(Box::new((parts, buf.into()))).into()),
+    }
+}
+
+/// Sets its flag when dropped.
+#[derive(Default)]
+struct Cancel(Arc<AtomicBool>);
+
+impl Drop for Cancel {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Accepts connections until the authorization response with `state` arrives, and returns its
+/// code. It gives up after `timeout`, or once `stopped` is set.
+fn receive(
+    listener: TcpListener,
+    state: &str,
+    timeout: Duration,
+    stopped: &AtomicBool,
+) -> Result<String> {
+    let deadline = Instant::now() + timeout;
+    listener.set_nonblocking(true).map_err(Error::Listener)?;
+    loop {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                if stopped.load(Ordering::SeqCst) {
+                    trace!("login is dropped, stop waiting for the authorization response");
+                    return Err(Error::AuthorizationTimeout(timeout));
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(Error::AuthorizationTimeout(timeout));
+                }
+                thread::sleep(ACCEPT_INTERVAL.min(deadline - now));
+                continue;
+            }
+            Err(err) => return Err(Error::Listener(err)),
+        };
+        stream.set_nonblocking(false).map_err(Error::Listener)?;
+        // A client that never sends its request does not hold up the listener.
+        stream
+            .set_read_timeout(Some(ACCEPT_INTERVAL * 10))
+            .map_err(Error::Listener)?;
+        let mut params = match read_query(&stream) {
+            Ok(query) => {
+                serde_urlencoded::from_str::<HashMap<String, String>>(&query).unwrap_or_default()
+            }
+            Err(err) => {
+                trace!("failed to read a request of the loopback listener: {}", err);
+                continue;
+            }
+        };
+
+        let (status, body, result) = match (params.remove("code"), params.remove("error")) {
+            // Responses of other authorization requests, including errors, are rejected and do not
+            // end the login, so keep waiting for the one with our state.
+            (Some(_), _) | (_, Some(_))
+                if params.get("state").map(String::as_str) != Some(state) =>
+            {
+                trace!("rejected an authorization response with a mismatched state");
+                ("400 Bad Request", "Authorization state mismatch.", None)
+            }
+            (_, Some(err)) => (
+                "200 OK",
+                "Authorization failed. You can close this window.",
+                Some(Err(Error::Authorization(err))),
+            ),
+            (Some(code), None) => (
+                "200 OK",
+                "Authorization succeeded. You can close this window.",
+                Some(Ok(code)),
+            ),
+            // e.g. a request for the favicon.
+            (None, None) => ("404 Not Found", "Not Found", None),
+        };
+        let mut stream = stream;
+        let _ = write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        if let Some(result) = result {
+            return result;
+        }
+    }
+}
+
+/// Reads the request head and returns the query of the request line.
+fn read_query(stream: &TcpStream) -> io::Result<String> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let target = line.split_whitespace().nth(1).unwrap_or_default();
+    let query = target.split_once('?').map(|(_, q)| q.to_owned());
+
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    Ok(query.unwrap_or_default())
+}
+
+fn save(user: &credentials::User) -> io::Result<()> {
+    let path = well_known_file_path();
+    trace!("write application default credentials to {:?}", path);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let json = serde_json::to_vec_pretty(&AuthorizedUser {
+        client_id: &user.client_id,
+        client_secret: &user.client_secret,
+        refresh_token: &user.refresh_token,
+        typ: "authorized_user",
+    })
+    .unwrap();
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(&json)
+}
+
+fn random(len: usize) -> String {
+    let mut buf = vec![0; len];
+    SystemRandom::new()
+        .fill(&mut buf)
+        .expect("system random number generator failed");
+    base64::encode_config(buf, base64::URL_SAFE_NO_PAD)
+}
+
+// https://datatracker.ietf.org/doc/html/rfc7636#section-4.2
+fn code_challenge(verifier: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, verifier.as_bytes());
+    base64::encode_config(hash, base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::Read as _,
+        sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    };
+
+    use futures_util::future::BoxFuture;
+    use hyper::Response;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_code_challenge() {
+        // https://datatracker.ietf.org/doc/html/rfc7636#appendix-B
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+        assert_eq!(random(32).len(), 43);
+    }
+
+    fn get(addr: &str, target: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, addr).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let addr = testing::serve(|_, body| {
+            let form = serde_urlencoded::from_bytes::<HashMap<String, String>>(&body).unwrap();
+            assert_eq!(form["grant_type"], "authorization_code");
+            assert_eq!(form["code"], "code");
+            assert_eq!(form["client_id"], "id");
+            assert_eq!(form["code_verifier"].len(), 43);
+            assert!(form["redirect_uri"].starts_with("http://127.0.0.1:"));
+            Response::new(
+                r#"{"access_token":"access","expires_in":3599,"refresh_token":"refresh","token_type":"Bearer"}"#.into(),
+            )
+        });
+
+        let mut login = Login::new("id", "secret").on_authorization_url(|url| {
+            let query = url.split_once('?').unwrap().1;
+            let params = serde_urlencoded::from_str::<HashMap<String, String>>(query).unwrap();
+            assert_eq!(params["code_challenge_method"], "S256");
+            assert_eq!(params["scope"], CLOUD_PLATFORM);
+            let addr = params["redirect_uri"]
+                .trim_start_matches("http://")
+                .to_owned();
+            let state = params["state"].clone();
+            thread::spawn(move || {
+                assert!(get(&addr, "/favicon.ico").starts_with("HTTP/1.1 404"));
+                let target = format!("/?state={}&code=code&scope=x", state);
+                assert!(get(&addr, &target).starts_with("HTTP/1.1 200"));
+            });
+        });
+        login.client = Client::https_or_http(CONNECT_TIMEOUT);
+        login.token_uri = format!("http://{}/token", addr).parse().unwrap();
+
+        assert_eq!(
+            login.run().await.unwrap(),
+            Credentials::User(credentials::User {
+                scopes: vec![CLOUD_PLATFORM.into()],
+                audience: None,
+                client_id: "id".into(),
+                client_secret: "secret".into(),
+                refresh_token: "refresh".into(),
+                token_uri: None,
+                quota_project_id: None,
+                universe_domain: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_receive() {
+        let stopped = AtomicBool::new(false);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        assert!(matches!(
+            receive(listener, "state", Duration::from_millis(50), &stopped),
+            Err(Error::AuthorizationTimeout(_))
+        ));
+
+        // Responses of other authorization requests are rejected, and the login keeps waiting.
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let client = thread::spawn(move || {
+            (
+                get(&addr, "/?state=other&error=access_denied"),
+                get(&addr, "/?state=other&code=other"),
+                get(&addr, "/?state=state&code=code"),
+            )
+        });
+        assert_eq!(
+            receive(listener, "state", Duration::from_secs(5), &stopped).unwrap(),
+            "code"
+        );
+        let (denied, other, ok) = client.join().unwrap();
+        assert!(denied.starts_with("HTTP/1.1 400"), "{}", denied);
+        assert!(other.starts_with("HTTP/1.1 400"), "{}", other);
+        assert!(ok.starts_with("HTTP/1.1 200"), "{}", ok);
+
+        stopped.store(true, Ordering::SeqCst);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        assert!(receive(listener, "state", Duration::from_secs(60), &stopped).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_device_login() {
+        static SLEPT: AtomicU64 = AtomicU64::new(0);
+
+        // Adds up the sleeps instead of sleeping.
+        struct Slept;
+
+        impl Timer for Slept {
+            fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+                SLEPT.fetch_add(duration.as_secs(), Ordering::SeqCst);
+                Box::pin(async {})
+            }
+        }
+
+        let polls = AtomicUsize::new(0);
+        let addr = testing::serve(move |parts, body| {
+            let form = serde_urlencoded::from_bytes::<HashMap<String, String>>(&body).unwrap();
+            let (status, body) = match parts.uri.path() {
+                "/device/code" => {
+                    assert_eq!(form["scope"], "scope1 scope2");
+                    (
+                        200,
+                        r#"{"device_code":"device","user_code":"ABC-DEF","verification_url":"https://www.google.com/device","expires_in":1800,"interval":5}"#,
+                    )
+                }
+                "/token" => {
+                    assert_eq!(form["device_code"], "device");
+                    match polls.fetch_add(1, Ordering::SeqCst) {
+                        0 => (428, r#"{"error":"authorization_pending"}"#),
+                        1 => (503, "unavailable"),
+                        2 => (403, r#"{"error":"slow_down"}"#),
+                        _ => (
+                            200,
+                            r#"{"access_token":"access","expires_in":3599,"refresh_token":"refresh","token_type":"Bearer"}"#,
+                        ),
+                    }
+                }
+                _ => unreachable!(),
+            };
+            Response::builder()
+                .status(status)
+                .body(body.into())
+                .unwrap()
+        });
+
+        let mut login = DeviceLogin::new("id", "secret")
+            .scopes(&["scope1", "scope2"])
+            .on_device_code(|code| {
+                assert_eq!(code.user_code, "ABC-DEF");
+                assert_eq!(code.expires_in, Duration::from_secs(1800));
+            });
+        login.client = Client::https_or_http(CONNECT_TIMEOUT);
+        login.device_code_uri = format!("http://{}/device/code", addr).parse().unwrap();
+        login.token_uri = format!("http://{}/token", addr).parse().unwrap();
+        login.timer = Some(Arc::new(Slept));
+
+        assert_eq!(
+            login.run().await.unwrap(),
+            Credentials::User(credentials::User {
+                scopes: vec!["scope1".into(), "scope2".into()],
+                audience: None,
+                client_id: "id".into(),
+                client_secret: "secret".into(),
+                refresh_token: "refresh".into(),
+                token_uri: None,
+                quota_project_id: None,
+                universe_domain: None,
+            })
+        );
+        // Transient errors are retried, and the interval is increased by 5 seconds after
+        // `slow_down`.
+        assert_eq!(SLEPT.load(Ordering::SeqCst), 5 + 5 + 5 + 10);
+    }
+
+    #[tokio::test]
+    async fn test_revoke() {
+        let addr = testing::serve(|parts, body| {
+            assert_eq!(parts.uri.path(), "/revoke");
+            assert_eq!(&body[..], b"token=refresh");
+            Response::new("{}".into())
+        });
+        let uri = format!("http://{}/revoke", addr).parse().unwrap();
+        revoke_at(&Client::https_or_http(CONNECT_TIMEOUT), &uri, "refresh")
+            .await
+            .unwrap();
+    }
+}
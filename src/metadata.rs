@@ -0,0 +1,349 @@
+use std::{
+    env, fmt, future::Future, net::Ipv6Addr, pin::Pin, str::FromStr as _, sync::Arc, time::Duration,
+};
+
+use futures_util::future::{pending, poll_fn, select, select_ok, BoxFuture, Either};
+use hyper::{
+    body::to_bytes,
+    client::HttpConnector,
+    header::{HeaderName, HeaderValue, USER_AGENT},
+    http::uri::{Authority, InvalidUri, PathAndQuery, Scheme},
+    Body, Request, StatusCode, Uri,
+};
+use tracing::trace;
+
+use crate::{
+    runtime::{self, Timer},
+    sync::RefGuard,
+};
+
+// https://cloud.google.com/compute/docs/metadata/querying-metadata
+const METADATA_IP: &str = "169.254.169.254";
+const METADATA_IPV6: &str = "[fd20:ce::254]";
+const METADATA_FLAVOR: HeaderName = HeaderName::from_static("metadata-flavor");
+
+/// How long the metadata server is waited for.
+#[derive(Clone)]
+pub(crate) struct Timeouts {
+    /// Only applies to the default http client.
+    pub connect: Duration,
+    /// Finding the metadata server, after which it is assumed to not exist.
+    pub probe: Duration,
+    /// Measures the probe timeout, which does not apply without it.
+    pub timer: Option<Arc<dyn Timer>>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(2),
+            probe: Duration::from_secs(3),
+            timer: runtime::default_timer(),
+        }
+    }
+}
+
+impl fmt::Debug for Timeouts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Timeouts")
+            .field("connect", &self.connect)
+            .field("probe", &self.probe)
+            .finish()
+    }
+}
+
+/// A client of the metadata server, which is found in the following order:
+/// - The endpoint set on the credentials builder.
+/// - The `GCE_METADATA_HOST` environment variable.
+/// - The `GCE_METADATA_IP` environment variable.
+/// - The IPv4 or IPv6 address of the metadata server, whichever answers first.
+#[derive(Clone)]
+pub(crate) struct Client {
+    inner: hyper::Client<HttpConnector>,
+    scheme: Scheme,
+    // The first endpoint is used once probing is done.
+    endpoints: Vec<Authority>,
+    probe: bool,
+    probe_timeout: Duration,
+    timer: Option<Arc<dyn Timer>>,
+    user_agent: HeaderValue,
+}
+
+impl Client {
+    pub fn new(
+        endpoint: Option<&str>,
+        client: Option<hyper::Client<HttpConnector>>,
+        timeouts: Timeouts,
+    ) -> Result<Self, InvalidUri> {
+        Self::with_env(endpoint, client, timeouts, |name| env::var(name).ok())
+    }
+
+    fn with_env(
+        endpoint: Option<&str>,
+        client: Option<hyper::Client<HttpConnector>>,
+        timeouts: Timeouts,
+        getenv: fn(&str) -> Option<String>,
+    ) -> Result<Self, InvalidUri> {
+        let (scheme, endpoints, probe) = match endpoint
+            .map(ToOwned::to_owned)
+            .or_else(|| getenv("GCE_METADATA_HOST"))
+        {
+            // The metadata server is known to exist.
+            Some(host) => {
+                let (scheme, authority) = parse_endpoint(&host)?;
+                (scheme, vec![authority], false)
+            }
+            None => {
+                let endpoints = match getenv("GCE_METADATA_IP") {
+                    Some(ip) => vec![parse_endpoint(&ip)?.1],
+                    None => vec![
+                        Authority::from_static(METADATA_IP),
+                        Authority::from_static(METADATA_IPV6),
+                    ],
+                };
+                (Scheme::HTTP, endpoints, true)
+            }
+        };
+        let inner = client.unwrap_or_else(|| {
+            let mut connector = HttpConnector::new();
+            connector.set_connect_timeout(Some(timeouts.connect));
+            hyper::Client::builder().build(connector)
+        });
+        Ok(Self {
+            inner,
+            scheme,
+            endpoints,
+            probe,
+            probe_timeout: timeouts.probe,
+            timer: timeouts.timer,
+            user_agent: HeaderValue::from_static(concat!(
+                "github.com/mechiru/",
+                env!("CARGO_PKG_NAME"),
+                " v",
+                env!("CARGO_PKG_VERSION")
+            )),
+        })
+    }
+
+    /// Reports whether the metadata server is reachable, and selects the endpoint that answered.
+    pub async fn on_gce(&mut self) -> gcemeta::Result<bool> {
+        if !self.probe {
+            return Ok(true);
+        }
+
+        let probes = self.endpoints.iter().map(|authority| {
+            let req = self.request(authority, PathAndQuery::from_static("/"));
+            let fut = self.inner.request(req);
+            let authority = authority.clone();
+            // Boxed as `Sync` too, since the future of building credentials is `Sync`.
+            Box::pin(async move {
+                let resp = fut.await?;
+                match resp.headers().get(METADATA_FLAVOR) {
+                    Some(v) if v == "Google" => Ok(authority),
+                    _ => Err(gcemeta::Error::StatusCode(resp.into_parts())),
+                }
+            })
+                as Pin<Box<dyn Future<Output = gcemeta::Result<Authority>> + Send + Sync>>
+        });
+        let timeout: BoxFuture<'static, ()> = match self.timer {
+            Some(ref timer) => timer.sleep(self.probe_timeout),
+            None => Box::pin(pending()),
+        };
+        let mut timeout = RefGuard::new(timeout);
+        let timeout = poll_fn(move |cx| timeout.get_mut().as_mut().poll(cx));
+        match select(select_ok(probes), timeout).await {
+            Either::Left((Ok((authority, _)), _)) => {
+                trace!("metadata server answered at {}", authority);
+                self.endpoints = vec![authority];
+                self.probe = false;
+                Ok(true)
+            }
+            Either::Left((Err(err), _)) => {
+                trace!("metadata server is not reachable: {:?}", err);
+                Ok(false)
+            }
+            Either::Right(_) => {
+                trace!(
+                    "metadata server did not answer within {:?}",
+                    self.probe_timeout
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    /// Gets a value with surrounding whitespace trimmed.
+    pub async fn get(&self, path_and_query: PathAndQuery) -> gcemeta::Result<String> {
+        let buf = self.get_bytes(path_and_query).await?;
+        let s = String::from_utf8(buf.to_vec())?;
+        Ok(s.trim().to_owned())
+    }
+
+    pub async fn get_as<T>(&self, path_and_query: PathAndQuery) -> gcemeta::Result<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let buf = self.get_bytes(path_and_query).await?;
+        Ok(serde_json::from_slice(&buf)?)
+    }
+
+    async fn get_bytes(&self, path_and_query: PathAndQuery) -> gcemeta::Result<bytes::Bytes> {
+        let req = self.request(&self.endpoints[0], path_and_query);
+        let (parts, body) = self.inner.request(req).await?.into_parts();
+        match parts.status {
+            StatusCode::OK => Ok(to_bytes(body).await?),
+            _ => Err(gcemeta::Error::StatusCode((parts, body))),
+        }
+    }
+
+    fn request(&self, authority: &Authority, path_and_query: PathAndQuery) -> Request<Body> {
+        let uri = Uri::builder()
+            .scheme(self.scheme.clone())
+            .authority(authority.clone())
+            .path_and_query(path_and_query)
+            .build()
+            .unwrap();
+        Request::get(uri)
+            .header(METADATA_FLAVOR, "Google")
+            .header(USER_AGENT, self.user_agent.clone())
+            .body(Body::empty())
+            .unwrap()
+    }
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("scheme", &self.scheme)
+            .field("endpoints", &self.endpoints)
+            .field("probe", &self.probe)
+            .field("probe_timeout", &self.probe_timeout)
+            .finish()
+    }
+}
+
+/// Parses `host[:port]`, a bare IPv6 address or a uri with a scheme.
+fn parse_endpoint(s: &str) -> Result<(Scheme, Authority), InvalidUri> {
+    if let Ok(ip) = s.parse::<Ipv6Addr>() {
+        return Ok((Scheme::HTTP, Authority::from_str(&format!("[{}]", ip))?));
+    }
+    let uri = if s.contains("://") {
+        Uri::from_str(s)?
+    } else {
+        Uri::from_str(&format!("http://{}", s))?
+    };
+    let parts = uri.into_parts();
+    Ok((
+        parts.scheme.unwrap_or(Scheme::HTTP),
+        parts.authority.expect("absolute uri has an authority"),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::Response;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_parse_endpoint() {
+        let parse = |s| {
+            let (scheme, authority) = parse_endpoint(s).unwrap();
+            format!("{}://{}", scheme, authority)
+        };
+        assert_eq!(
+            parse("metadata.google.internal"),
+            "http://metadata.google.internal"
+        );
+        assert_eq!(parse("127.0.0.1:8080"), "http://127.0.0.1:8080");
+        assert_eq!(parse("fd20:ce::254"), "http://[fd20:ce::254]");
+        assert_eq!(parse("[fd20:ce::254]:80"), "http://[fd20:ce::254]:80");
+        assert_eq!(parse("https://localhost:8443"), "https://localhost:8443");
+        assert!(parse_endpoint("http://").is_err());
+    }
+
+    #[test]
+    fn test_with_env() {
+        let client = Client::with_env(None, None, Default::default(), |_| None).unwrap();
+        assert_eq!(client.endpoints, [METADATA_IP, METADATA_IPV6]);
+        assert!(client.probe);
+
+        let client = Client::with_env(None, None, Default::default(), |name| match name {
+            "GCE_METADATA_IP" => Some("fd20:ce::254".into()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(client.endpoints, [METADATA_IPV6]);
+        assert!(client.probe);
+
+        let client = Client::with_env(None, None, Default::default(), |name| match name {
+            "GCE_METADATA_HOST" => Some("metadata.google.internal".into()),
+            _ => Some("169.254.169.254".into()),
+        })
+        .unwrap();
+        assert_eq!(client.endpoints, ["metadata.google.internal"]);
+        assert!(!client.probe);
+
+        let client = Client::with_env(Some("localhost:8080"), None, Default::default(), |_| {
+            Some("metadata.google.internal".into())
+        })
+        .unwrap();
+        assert_eq!(client.endpoints, ["localhost:8080"]);
+    }
+
+    #[tokio::test]
+    async fn test_on_gce() {
+        let addr = testing::serve(|parts, _| {
+            assert_eq!(parts.headers[METADATA_FLAVOR], "Google");
+            let body = match parts.uri.path() {
+                "/" => "",
+                "/computeMetadata/v1/project/project-id" => "project\n",
+                _ => unreachable!(),
+            };
+            Response::builder()
+                .header(METADATA_FLAVOR, "Google")
+                .body(body.into())
+                .unwrap()
+        });
+
+        let mut client = Client::with_env(None, None, Default::default(), |_| None).unwrap();
+        // The first endpoint does not answer.
+        client.endpoints = vec![
+            Authority::from_static("127.0.0.1:1"),
+            Authority::from_str(&addr.to_string()).unwrap(),
+        ];
+        assert!(client.on_gce().await.unwrap());
+        assert_eq!(client.endpoints, [addr.to_string().as_str()]);
+        assert_eq!(
+            client
+                .get(PathAndQuery::from_static(
+                    "/computeMetadata/v1/project/project-id"
+                ))
+                .await
+                .unwrap(),
+            "project"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probe_timeout() {
+        // Connections are accepted by the backlog, but never answered.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = Client::with_env(
+            None,
+            None,
+            Timeouts {
+                probe: Duration::from_millis(100),
+                timer: Some(Arc::new(testing::Sleep)),
+                ..Default::default()
+            },
+            |_| None,
+        )
+        .unwrap();
+        client.endpoints =
+            vec![Authority::from_str(&listener.local_addr().unwrap().to_string()).unwrap()];
+        assert!(!client.on_gce().await.unwrap());
+        assert!(client.probe);
+    }
+}
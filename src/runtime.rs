@@ -0,0 +1,41 @@
+use std::{sync::Arc, time::Duration};
+
+use futures_util::future::BoxFuture;
+
+/// Spawns background tasks, such as the token refresher.
+pub trait Executor: Send + Sync + 'static {
+    fn spawn(&self, future: BoxFuture<'static, ()>);
+}
+
+/// Creates timers for background tasks and retry backoffs.
+pub trait Timer: Send + Sync + 'static {
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// Spawns tasks on the current tokio runtime and uses its timers.
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tokio;
+
+#[cfg(feature = "tokio")]
+impl Executor for Tokio {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        tokio::spawn(future);
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Timer for Tokio {
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Returns tokio with the `tokio` feature, and no timer otherwise, in which case nothing that
+/// needs one waits for a timeout.
+pub(crate) fn default_timer() -> Option<Arc<dyn Timer>> {
+    #[cfg(feature = "tokio")]
+    return Some(Arc::new(Tokio));
+    #[cfg(not(feature = "tokio"))]
+    return None;
+}
@@ -1,7 +1,9 @@
 use std::{
     fmt,
     future::{self, Ready},
+    sync::Arc,
     task::{self, Poll},
+    time::Duration,
 };
 
 use futures_util::{
@@ -11,8 +13,9 @@ use futures_util::{
 use hyper::Request;
 
 use crate::{
-    auth::{self, Auth, Config},
+    auth::{self, ApiKeyPlacement, Auth, Config, RefreshStatus, RefreshWindow},
     credentials::Credentials,
+    runtime::{Executor, Timer},
 };
 
 /// Represents an inner service error or Google authentication error.
@@ -48,12 +51,147 @@ impl<S> Builder<S> {
         self
     }
 
+    /// Retries a failed token fetch up to `max_retry` times, waiting for the
+    /// [`retry_backoff`](Self::retry_backoff) in between if there is a [`timer`](Self::timer).
     #[must_use]
     pub fn max_retry(mut self, max_retry: u8) -> Self {
         self.config.max_retry = max_retry;
         self
     }
 
+    /// Sets the backoff before the first retry of a failed token fetch, which doubles with each
+    /// retry up to `max`. Up to half of it is cut at random. A longer `Retry-After` of a 429 or
+    /// 503 response is waited instead. The defaults are 200 milliseconds and 10 seconds.
+    #[must_use]
+    pub fn retry_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.config.retry.initial_backoff = initial;
+        self.config.retry.max_backoff = max;
+        self
+    }
+
+    /// Stops retrying a failed token fetch once the next retry would start this long after the
+    /// first attempt, even if fewer than `max_retry` retries were made. Errors that are not
+    /// [retryable](crate::AuthError::is_retryable) are never retried. The default is 30 seconds.
+    #[must_use]
+    pub fn max_retry_elapsed(mut self, max_elapsed: Duration) -> Self {
+        self.config.retry.max_elapsed = max_elapsed;
+        self
+    }
+
+    /// Sets how long connecting to token endpoints may take. Requests fail with
+    /// `AuthError::Timeout` when it passes, which is retried. The default is 5 seconds.
+    #[must_use]
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = timeout;
+        self
+    }
+
+    /// Sets how long each attempt to fetch a token may take in total, so that a token endpoint
+    /// that does not answer does not stall requests forever. The attempt fails with
+    /// `AuthError::Timeout` when it passes, which is retried. It is measured with the
+    /// [`timer`](Self::timer). The default is 20 seconds.
+    #[must_use]
+    pub fn fetch_timeout(mut self, timeout: Duration) -> Self {
+        self.config.fetch_timeout = timeout;
+        self
+    }
+
+    /// Sets how long no token is fetched after fetching fails `max_retry` times in a row, so that
+    /// an outage of the token endpoint does not cause a storm of requests. Requests fail with
+    /// `AuthError::RefreshCooldown` meanwhile, unless the current token is kept, see
+    /// [`keep_valid_token`](Self::keep_valid_token). The default is 5 seconds.
+    #[must_use]
+    pub fn retry_cooldown(mut self, cooldown: Duration) -> Self {
+        self.config.retry_cooldown = cooldown;
+        self
+    }
+
+    /// Keeps using the current token until it expires when refreshing it fails, so that requests
+    /// only fail once no valid token is left. The refresh is retried after the
+    /// [`retry_cooldown`](Self::retry_cooldown). The default is `true`.
+    #[must_use]
+    pub fn keep_valid_token(mut self, enabled: bool) -> Self {
+        self.config.keep_valid_token = enabled;
+        self
+    }
+
+    /// Refreshes tokens this long before they expire, while requests keep using them. The
+    /// default is 225 seconds. Tokens are used for at least half of their lifetime.
+    #[must_use]
+    pub fn refresh_margin(mut self, margin: Duration) -> Self {
+        self.config.refresh.window = RefreshWindow::Margin(margin);
+        self
+    }
+
+    /// Refreshes tokens once `fraction` of their lifetime has elapsed, while requests keep using
+    /// them. It replaces the [`refresh_margin`](Self::refresh_margin).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `fraction` is not between 0.5 and 1, since tokens are used for at least half of
+    /// their lifetime.
+    #[must_use]
+    pub fn refresh_fraction(mut self, fraction: f64) -> Self {
+        assert!(
+            (0.5..=1.0).contains(&fraction),
+            "refresh fraction must be between 0.5 and 1: {}",
+            fraction
+        );
+        self.config.refresh.window = RefreshWindow::Fraction(fraction);
+        self
+    }
+
+    /// Refreshes tokens up to `jitter` earlier at random, so that many clients do not refresh at
+    /// the same time. The default is zero.
+    #[must_use]
+    pub fn refresh_jitter(mut self, jitter: Duration) -> Self {
+        self.config.refresh.jitter = jitter;
+        self
+    }
+
+    /// Refreshes tokens in a background task, so that requests after an idle period do not wait
+    /// for a new token. The task is spawned on `runtime`, such as [`Tokio`](crate::Tokio) with
+    /// the `tokio` feature, and stops when the last clone of the service is dropped. Only the
+    /// default token is refreshed in the background, not the tokens of
+    /// [`subjects`](Self::subjects). See [`GoogleAuthz::refresh_status`] for the outcome of
+    /// refreshes.
+    #[must_use]
+    pub fn background_refresh<R: Executor + Timer>(mut self, runtime: R) -> Self {
+        let runtime = Arc::new(runtime);
+        self.config.background_refresh = Some((runtime.clone(), runtime));
+        self
+    }
+
+    /// Sets the timer of retry backoffs and fetch timeouts. The default is tokio with the `tokio`
+    /// feature. Without a timer, fetches do not time out and failed fetches are retried
+    /// immediately instead of after the backoff.
+    #[must_use]
+    pub fn timer(mut self, timer: impl Timer) -> Self {
+        self.config.timer = Some(Arc::new(timer));
+        self
+    }
+
+    /// Registers the Google Workspace users that requests can act as through the
+    /// [`Subject`](crate::Subject) extension. Their tokens are fetched and refreshed along with
+    /// the default one, without the service waiting for them: requests acting as a user fail
+    /// while its token is not fetched yet, as do requests acting as any other user.
+    #[must_use]
+    pub fn subjects<I>(mut self, subjects: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.config.subjects = subjects.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets where API keys are put in requests. The default is the `x-goog-api-key` header.
+    #[must_use]
+    pub fn api_key_placement(mut self, placement: ApiKeyPlacement) -> Self {
+        self.config.api_key_placement = placement;
+        self
+    }
+
     #[must_use]
     pub fn credentials(mut self, credentials: impl Into<Option<Credentials>>) -> Self {
         self.credentials = credentials.into();
@@ -98,6 +236,14 @@ impl GoogleAuthz<()> {
     }
 }
 
+impl<S> GoogleAuthz<S> {
+    /// Returns the outcome of token refreshes, or `None` for credentials without OAuth 2.0
+    /// tokens.
+    pub fn refresh_status(&self) -> Option<RefreshStatus> {
+        self.auth.refresh_status()
+    }
+}
+
 impl<S: Clone> Clone for GoogleAuthz<S> {
     fn clone(&self) -> Self {
         Self {
@@ -183,4 +329,16 @@ mod test {
         assert_send(&svc);
         assert_sync(&svc);
     }
+
+    #[test]
+    #[should_panic(expected = "refresh fraction must be between 0.5 and 1")]
+    fn test_refresh_fraction_nan() {
+        let _ = Builder::new(()).refresh_fraction(f64::NAN);
+    }
+
+    #[test]
+    #[should_panic(expected = "refresh fraction must be between 0.5 and 1")]
+    fn test_refresh_fraction_below_half() {
+        let _ = Builder::new(()).refresh_fraction(0.3);
+    }
 }
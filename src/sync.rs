@@ -1,4 +1,15 @@
-use std::fmt;
+use std::{
+    fmt, mem,
+    sync::Arc,
+    task::{Poll, Waker},
+    thread,
+};
+
+use futures_util::{
+    future::{poll_fn, BoxFuture},
+    task::ArcWake,
+};
+use parking_lot::Mutex;
 
 /// RefGuard wraps a `Send` type to make it `Sync`, by ensuring that it is only
 /// ever accessed through a &mut pointer.
@@ -23,3 +34,73 @@ impl<T: fmt::Debug> fmt::Debug for RefGuard<T> {
 }
 
 unsafe impl<T: Send> Sync for RefGuard<T> {}
+
+/// Wakers of every task waiting for a shared future. The future is polled with the waker of
+/// this set, so that all tasks are woken whichever of them polled it last.
+#[derive(Default)]
+pub(crate) struct Wakers(Mutex<(u64, Vec<Waker>)>);
+
+impl Wakers {
+    /// Runs `poll`, which polls the shared future, and registers `waker` only if it is pending.
+    /// If the future wakes the set between `poll` and the registration, `waker` is woken at once.
+    pub fn poll<T>(&self, waker: &Waker, poll: impl FnOnce() -> Poll<T>) -> Poll<T> {
+        let generation = self.0.lock().0;
+        let result = poll();
+        if result.is_pending() {
+            let mut wakers = self.0.lock();
+            if wakers.0 != generation {
+                waker.wake_by_ref();
+            } else if !wakers.1.iter().any(|w| w.will_wake(waker)) {
+                wakers.1.push(waker.clone());
+            }
+        }
+        result
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.0.lock().1.len()
+    }
+}
+
+impl ArcWake for Wakers {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        // Woken tasks register again if they are still waiting.
+        let wakers = {
+            let mut wakers = arc_self.0.lock();
+            wakers.0 = wakers.0.wrapping_add(1);
+            mem::take(&mut wakers.1)
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Runs `f` on a dedicated thread so that blocking on it does not block the caller.
+pub(crate) fn blocking<T, F>(f: F) -> BoxFuture<'static, T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let shared = Arc::new(Mutex::new((None, None::<std::task::Waker>)));
+    let sender = shared.clone();
+    thread::spawn(move || {
+        let value = f();
+        let mut guard = sender.lock();
+        guard.0 = Some(value);
+        if let Some(waker) = guard.1.take() {
+            waker.wake();
+        }
+    });
+    Box::pin(poll_fn(move |cx| {
+        let mut guard = shared.lock();
+        match guard.0.take() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                guard.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }))
+}
@@ -0,0 +1,69 @@
+// Fetching a token must not leave threads behind, such as ones sleeping for a timeout.
+#![cfg(target_os = "linux")]
+
+use std::{fs, time::Duration};
+
+use futures_util::future::poll_fn;
+use google_authz::{Credentials, GoogleAuthz};
+use hyper::{Body, Request};
+use tower_service::Service;
+
+fn threads() -> usize {
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Threads:"))
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_no_threads_left_after_fetch() {
+    let credentials = Credentials::builder()
+        .access_token("token", None)
+        .refresh_access_token(|| async { Ok::<_, std::io::Error>(("refreshed", None)) })
+        .build()
+        .await
+        .unwrap();
+    let mut service = GoogleAuthz::builder(Echo)
+        .credentials(credentials)
+        .build()
+        .await;
+
+    let before = threads();
+    poll_fn(|cx| service.poll_ready(cx)).await.unwrap();
+    let req = Request::get("https://example.com")
+        .body(Body::empty())
+        .unwrap();
+    let resp = service.call(req).await.unwrap();
+    assert_eq!(resp, "Bearer token");
+    // Threads that exit on their own are given the time to.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(threads(), before);
+}
+
+// Answers with the authorization header of the request.
+#[derive(Clone)]
+struct Echo;
+
+impl Service<Request<Body>> for Echo {
+    type Response = String;
+    type Error = std::convert::Infallible;
+    type Future = futures_util::future::Ready<Result<String, Self::Error>>;
+
+    fn poll_ready(
+        &mut self,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let value = req.headers()[hyper::header::AUTHORIZATION]
+            .to_str()
+            .unwrap();
+        futures_util::future::ready(Ok(value.to_owned()))
+    }
+}